@@ -24,30 +24,190 @@
 //!
 //! The URLs in the index files are sorted alpha-numerically.
 //!
-//! Once the batcher has downloaded (parts of) an index file, it will filter out URLs that are not in English or that did not return a 200 HTTP status code, batch them into groups whose size has a constant upper limit and push the messages containing these URls into a RabbitMQ queue.
+//! The batcher can process several crawls at once. It merges their indices in SURT order and only keeps
+//! the newest capture of every URL, optionally preferring crawls with a higher `--crawl-priorities`, see [MergedCdxIndex].
+//!
+//! Once the batcher has downloaded (parts of) an index file, it will filter out URLs that are not in one of the `--languages` or that did not return a 200 HTTP status code, batch them into groups whose size has a constant upper limit and push the messages containing these URls into a RabbitMQ queue.
+//! By default, a URL is kept if any of its languages is a target language. With `--language-match primary`, only its primary language counts.
+//!
+//! URLs on one of the `--blocklist` files, e.g. adult or malware domains, are skipped and counted per category.
+//! The blocklist is reloaded when one of its files changes, so that it can be updated during long runs.
+//!
+//! Identical pages are often crawled under many URLs. The batcher skips entries whose `digest`, the SHA-1 of the payload,
+//! it has already seen. The seen digests are kept in a [BloomFilter] with bounded memory, which can be persisted
+//! with `--dedup-filter` to skip pages that were already published in previous runs.
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use anyhow::Context;
 use clap::Parser;
+use lazy_static::lazy_static;
 use pipeline::{
-    commoncrawl::{download_and_unzip, parse_cdx_line, parse_cluster_idx},
+    blocklist::{reload_on_change, Blocklist, BlocklistFile},
+    bloom::BloomFilter,
+    commoncrawl::{parse_cluster_idx, CdxEntry, LanguageMatch},
+    crawls::{CrawlIndex, MergedCdxIndex, DEFAULT_CRAWL},
     rabbitmq::{
         publish_batch, rabbitmq_channel_with_queue, rabbitmq_connection, BATCH_SIZE, CC_QUEUE_NAME,
+        DEFAULT_PREFETCH_COUNT,
     },
     tracing_and_metrics::{run_metrics_server, setup_tracing},
 };
+use prometheus::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
 use std::fs;
 
+lazy_static! {
+    static ref SKIPPED_DUPLICATES_COUNTER: IntCounter = register_int_counter!(
+        "batcher_skipped_duplicates",
+        "Number of cdx entries that were skipped because their digest has been seen before"
+    )
+    .unwrap();
+    static ref BLOCKED_URLS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "batcher_blocked_urls",
+        "Number of cdx entries that were skipped because their URL is on a blocklist, per category and rule kind",
+        &["category", "kind"]
+    )
+    .unwrap();
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// The IDs of the crawls that are processed, e.g. `CC-MAIN-2024-30,CC-MAIN-2024-26`.
+    /// URLs that occur in several crawls are only processed once, see `--crawl-priorities`.
+    #[arg(long, value_delimiter = ',', default_value = DEFAULT_CRAWL)]
+    crawls: Vec<String>,
+
+    /// The cluster.idx files of the crawls, in the same order as `--crawls`.
     /// For an explanation for why this file needs to be provided, please
     /// see Readme.md, section "Why do we download the cluster.idx file up front?".
-    #[arg(short, long, default_value = "cluster.idx")]
-    cluster_idx_filename: String,
+    #[arg(short, long, value_delimiter = ',', default_value = "cluster.idx")]
+    cluster_idx_filename: Vec<String>,
+
+    /// The priorities of the crawls, in the same order as `--crawls`. Of all captures of a URL,
+    /// the one from the crawl with the highest priority is processed, and among those the newest one.
+    /// By default, all crawls have the same priority, so that the newest capture is processed.
+    #[arg(long, value_delimiter = ',')]
+    crawl_priorities: Vec<i64>,
 
     /// This command line argument can be used to limit the number of chunks that should be processed.
-    /// If set, the batcher only processes so many lines from the provided cluster.idx file.
-    /// Otherwise, it processes all entries in the file.
+    /// If set, the batcher only processes so many lines from the cluster.idx file of every crawl.
+    /// Otherwise, it processes all entries in the files.
     #[arg(short, long)]
     num_cdx_chunks_to_process: Option<usize>,
+
+    /// The target languages as ISO 639-3 codes, as they appear in the `languages` field of the cdx index.
+    #[arg(long, value_delimiter = ',', default_value = "eng")]
+    languages: Vec<String>,
+
+    /// Whether the primary language of a URL or any of its languages has to be a target language.
+    #[arg(long, value_enum, default_value_t)]
+    language_match: LanguageMatch,
+
+    /// File in which the Bloom filter of seen digests is persisted across runs.
+    /// It is loaded at startup if it exists. Without it, digests are only deduplicated within a run.
+    #[arg(long)]
+    dedup_filter: Option<PathBuf>,
+
+    /// Number of digests for which a new Bloom filter is sized.
+    #[arg(long, default_value_t = 100_000_000)]
+    dedup_expected_digests: usize,
+
+    /// False positive rate of a new Bloom filter once it holds the expected number of digests.
+    /// A false positive makes the batcher skip a page that it has not seen before.
+    #[arg(long, default_value_t = 0.01)]
+    dedup_false_positive_rate: f64,
+
+    /// Blocklist files whose URLs are skipped, as `<category>=<path>` or `<path>`, in which case
+    /// the category is the file name. See the blocklist module for the format of the files.
+    #[arg(long, value_delimiter = ',')]
+    blocklist: Vec<BlocklistFile>,
+
+    /// Seconds between checks whether a blocklist file has changed, in which case the blocklist is reloaded.
+    /// Set to 0 to disable reloading.
+    #[arg(long, default_value_t = 60)]
+    blocklist_reload_interval: u64,
+
+    /// Number of processed cdx chunks after which the Bloom filter is saved to `--dedup-filter`.
+    #[arg(long, default_value_t = 100)]
+    dedup_save_interval: usize,
+}
+
+/// Reads the cluster.idx files of the crawls.
+fn crawl_indices(args: &Args) -> Result<Vec<CrawlIndex>, anyhow::Error> {
+    anyhow::ensure!(
+        args.cluster_idx_filename.len() == args.crawls.len(),
+        "Expected one cluster.idx file per crawl"
+    );
+    anyhow::ensure!(
+        args.crawl_priorities.is_empty() || args.crawl_priorities.len() == args.crawls.len(),
+        "Expected one priority per crawl"
+    );
+    let mut crawls = Vec::new();
+    for (i, (crawl, cluster_idx_filename)) in args
+        .crawls
+        .iter()
+        .zip(&args.cluster_idx_filename)
+        .enumerate()
+    {
+        let chunks = fs::read_to_string(cluster_idx_filename)
+            .with_context(|| format!("Failed to read {}", cluster_idx_filename))?
+            .lines()
+            .filter_map(parse_cluster_idx)
+            .take(args.num_cdx_chunks_to_process.unwrap_or(usize::MAX))
+            .collect();
+        let priority = args.crawl_priorities.get(i).copied().unwrap_or(0);
+        crawls.push(CrawlIndex::new(crawl.clone(), priority, chunks));
+    }
+    Ok(crawls)
+}
+
+/// Loads the Bloom filter from `path` if it exists and creates a new one otherwise.
+fn load_or_create_filter(args: &Args) -> BloomFilter {
+    let filter = match args.dedup_filter.as_deref() {
+        Some(path) if path.exists() => BloomFilter::load(path).unwrap(),
+        _ => BloomFilter::new(args.dedup_expected_digests, args.dedup_false_positive_rate),
+    };
+    tracing::info!(
+        "Using a Bloom filter of {} MB for digest deduplication",
+        filter.size_in_bytes() / 1_000_000
+    );
+    filter
+}
+
+fn save_filter(filter: &BloomFilter, path: Option<&Path>) {
+    if let Some(path) = path {
+        filter.save(path).unwrap();
+        tracing::info!("Saved Bloom filter to {}", path.display());
+    }
+}
+
+/// Returns whether the URL of the entry is on the blocklist and counts it if so.
+fn is_blocked(blocklist: &RwLock<Blocklist>, entry: &CdxEntry) -> bool {
+    let blocklist = blocklist.read().unwrap();
+    let Some(found) = blocklist.matches(&entry.metadata.url) else {
+        return false;
+    };
+    BLOCKED_URLS_COUNTER
+        .with_label_values(&[found.category, found.kind.as_str()])
+        .inc();
+    true
+}
+
+/// Returns whether the digest of the entry has been seen before and records it otherwise.
+/// Entries without a digest are never duplicates.
+fn is_duplicate(seen_digests: &mut BloomFilter, entry: &CdxEntry) -> bool {
+    let Some(digest) = entry.metadata.digest.as_deref() else {
+        return false;
+    };
+    let duplicate = seen_digests.insert(digest.as_bytes());
+    if duplicate {
+        SKIPPED_DUPLICATES_COUNTER.inc();
+    }
+    duplicate
 }
 
 #[tokio::main]
@@ -57,58 +217,63 @@ async fn main() {
     tokio::task::spawn(run_metrics_server(9000));
 
     let rabbit_conn = rabbitmq_connection().await.unwrap();
-    let (channel, _queue) = rabbitmq_channel_with_queue(&rabbit_conn, CC_QUEUE_NAME)
-        .await
-        .unwrap();
-
-    let idx = fs::read_to_string(args.cluster_idx_filename)
-        .expect("Should have been able to read the file")
-        .lines()
-        .filter_map(parse_cluster_idx)
-        .collect::<Vec<_>>();
-
-    let mut num_cdx_chunks_processed: usize = 0;
-    for cdx_chunk in idx {
-        print!(".");
-        let english_cdx_entries = String::from_utf8(
-            download_and_unzip(
-                &format!(
-                    "https://data.commoncrawl.org/cc-index/collections/CC-MAIN-2024-30/indexes/{}",
-                    cdx_chunk.cdx_filename
-                ),
-                cdx_chunk.cdx_offset,
-                cdx_chunk.cdx_length,
-            )
+    let (channel, _queue) =
+        rabbitmq_channel_with_queue(&rabbit_conn, CC_QUEUE_NAME, DEFAULT_PREFETCH_COUNT)
             .await
-            .unwrap(),
-        )
-        .unwrap()
-        .lines()
-        .map(parse_cdx_line)
-        .filter(|e| {
-            if let Some(languages) = e.metadata.languages.as_ref() {
-                languages.contains("eng") && e.metadata.status == 200
-            } else {
-                false
-            }
-        })
-        .collect::<Vec<_>>();
+            .unwrap();
+
+    let mut seen_digests = load_or_create_filter(&args);
+
+    let blocklist = Arc::new(RwLock::new(Blocklist::load(&args.blocklist).unwrap()));
+    tracing::info!(
+        "Loaded blocklist with {} rules",
+        blocklist.read().unwrap().len()
+    );
+    if !args.blocklist.is_empty() && args.blocklist_reload_interval > 0 {
+        reload_on_change(
+            blocklist.clone(),
+            args.blocklist.clone(),
+            Duration::from_secs(args.blocklist_reload_interval),
+        );
+    }
+
+    let crawls = crawl_indices(&args).unwrap();
+    let mut index = MergedCdxIndex::new(crawls, |e: &CdxEntry| {
+        e.metadata
+            .matches_languages(&args.languages, args.language_match)
+            && e.metadata.status == 200
+            && !is_blocked(&blocklist, e)
+    });
 
-        for batch in english_cdx_entries.as_slice().chunks(BATCH_SIZE) {
-            publish_batch(&channel, CC_QUEUE_NAME, batch).await;
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    let mut num_chunks_saved: usize = 0;
+    while let Some(entry) = index.next().await.unwrap() {
+        if !is_duplicate(&mut seen_digests, &entry) {
+            batch.push(entry);
         }
-        num_cdx_chunks_processed += 1;
-        if let Some(to_process) = args.num_cdx_chunks_to_process {
-            if to_process == num_cdx_chunks_processed {
-                break;
+        if batch.len() == BATCH_SIZE {
+            publish_batch(&channel, CC_QUEUE_NAME, &batch).await;
+            batch.clear();
+        }
+        // The filter is saved only after the pending batch has been published, so that a crash never loses entries.
+        if index.num_chunks_downloaded() >= num_chunks_saved + args.dedup_save_interval.max(1) {
+            if !batch.is_empty() {
+                publish_batch(&channel, CC_QUEUE_NAME, &batch).await;
+                batch.clear();
             }
+            save_filter(&seen_digests, args.dedup_filter.as_deref());
+            num_chunks_saved = index.num_chunks_downloaded();
         }
     }
+    if !batch.is_empty() {
+        publish_batch(&channel, CC_QUEUE_NAME, &batch).await;
+    }
+    save_filter(&seen_digests, args.dedup_filter.as_deref());
 }
 
 #[cfg(test)]
 mod tests {
-    use pipeline::commoncrawl::{parse_cdx_line, parse_cluster_idx};
+    use pipeline::commoncrawl::{parse_cdx_line, parse_cluster_idx, LanguageMatch};
 
 
     #[test]
@@ -120,6 +285,25 @@ mod tests {
         assert_eq!(cdx.len(), 3);
     }
 
+    #[test]
+    fn can_match_languages() {
+        let entry = parse_cdx_line(
+            r#"0,100,59,139)/ 20240723213521 {"url": "https://139.59.100.0/", "status": "200", "length": "16650", "offset": "64016172", "filename": "a.warc.gz", "languages": "ind,eng"}"#,
+        );
+        let targets =
+            |languages: &[&str]| languages.iter().map(|l| l.to_string()).collect::<Vec<_>>();
+        assert_eq!(entry.metadata.primary_language(), Some("ind"));
+        assert!(entry
+            .metadata
+            .matches_languages(&targets(&["eng"]), LanguageMatch::Any));
+        assert!(!entry
+            .metadata
+            .matches_languages(&targets(&["eng"]), LanguageMatch::Primary));
+        assert!(entry
+            .metadata
+            .matches_languages(&targets(&["deu", "ind"]), LanguageMatch::Primary));
+    }
+
     #[test]
     fn can_parse_cluster_idx_file_with_four_lines() {
         let content = r#"0,100,22,165)/ 20240722120756   cdx-00000.gz    0       188224  1
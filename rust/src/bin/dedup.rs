@@ -0,0 +1,360 @@
+//! Finds near-duplicate documents and boilerplate paragraphs in the output of the workers and removes them.
+//!
+//! The workers write a MinHash signature shard next to every output shard if `[minhash]` is enabled in their config.
+//! Deduplication then runs in two steps:
+//!
+//! 1. `dedup cluster` reads all signature shards, puts the documents into LSH buckets, merges candidates whose
+//!    estimated similarity is high enough into clusters and writes a list with a keep/drop decision for every
+//!    document that has duplicates. The longest document of every cluster is kept.
+//! 2. `dedup apply` copies all output shards and their token shards into a new directory, without the dropped documents.
+//!
+//! All signatures have to fit into memory, which is about half a kilobyte per document with the default settings.
+//!
+//! `dedup paragraphs` removes boilerplate paragraphs, such as navigation menus and legal footers, that occur in
+//! more than `--max-occurrences` documents, see [pipeline::paragraph_dedup]. It reads the output shards twice,
+//! first to count the paragraphs and then to write the shards without the frequent paragraphs.
+//! Since the token IDs no longer match the text, it tokenizes the documents again with the `[tokenizer]` section
+//! of `--worker-config` if the input has token shards.
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use pipeline::{
+    minhash::{find_duplicate_clusters, SignatureRecord, SIGNATURE_SHARD_EXTENSION},
+    output::{find_shards, read_shard, write_shard, OutputRecord, SHARD_EXTENSION},
+    paragraph_dedup::{remove_frequent_paragraphs_from_record, ParagraphCounter},
+    tokenizer::{
+        has_token_shard, read_token_shard, token_shard_path, write_token_shard, DocumentTokenizer,
+        TokenizerConfig,
+    },
+    tracing_and_metrics::setup_tracing,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Clusters near-duplicates and writes the keep/drop decisions.
+    Cluster {
+        /// Directory that contains the signature shards, searched recursively.
+        #[arg(short, long, default_value = "output")]
+        input_dir: PathBuf,
+
+        /// Number of LSH bands. The number of permutations of the signatures must be divisible by it.
+        /// More bands find duplicates with a lower similarity.
+        #[arg(long, default_value_t = 14)]
+        bands: usize,
+
+        /// Minimum estimated Jaccard similarity of two documents in the same bucket to be considered duplicates.
+        #[arg(long, default_value_t = 0.7)]
+        min_similarity: f64,
+
+        /// File into which the decisions are written as JSON lines.
+        #[arg(short, long, default_value = "dedup.jsonl")]
+        output: PathBuf,
+    },
+    /// Copies the output shards without the documents that were dropped by `cluster`.
+    Apply {
+        /// The file that `cluster` wrote.
+        #[arg(short, long, default_value = "dedup.jsonl")]
+        decisions: PathBuf,
+
+        /// Directory that contains the output shards, searched recursively.
+        #[arg(short, long, default_value = "output")]
+        input_dir: PathBuf,
+
+        /// Directory into which the deduplicated shards are written, with the same relative paths.
+        #[arg(short, long)]
+        output_dir: PathBuf,
+    },
+    /// Copies the output shards without paragraphs that occur in many documents.
+    Paragraphs {
+        /// Directory that contains the output shards, searched recursively.
+        #[arg(short, long, default_value = "output")]
+        input_dir: PathBuf,
+
+        /// Directory into which the deduplicated shards are written, with the same relative paths.
+        #[arg(short, long)]
+        output_dir: PathBuf,
+
+        /// Paragraphs that occur in more documents are removed.
+        #[arg(long, default_value_t = 100)]
+        max_occurrences: usize,
+
+        /// Directory for the temporary bucket files of the paragraph counts.
+        /// It needs about 8 bytes per paragraph of the corpus.
+        #[arg(long, default_value = "dedup-paragraphs-tmp")]
+        work_dir: PathBuf,
+
+        /// Number of bucket files. One bucket at a time is loaded into memory.
+        #[arg(long, default_value_t = 256)]
+        num_buckets: usize,
+
+        /// Worker config whose `[tokenizer]` section is used to count the tokens of the changed documents
+        /// and to write their token shards. Required if the input has token shards.
+        #[arg(long)]
+        worker_config: Option<PathBuf>,
+    },
+}
+
+/// Whether a document with duplicates is kept, and the document that is kept from its cluster.
+#[derive(Debug, Serialize, Deserialize)]
+struct Decision {
+    id: String,
+    url: String,
+    keep: bool,
+    kept_id: String,
+}
+
+fn cluster(
+    input_dir: PathBuf,
+    bands: usize,
+    min_similarity: f64,
+    output: PathBuf,
+) -> Result<(), anyhow::Error> {
+    let mut records: Vec<SignatureRecord> = Vec::new();
+    for shard in find_shards(&input_dir, SIGNATURE_SHARD_EXTENSION)? {
+        records.extend(read_shard::<SignatureRecord>(&shard)?);
+    }
+    tracing::info!("Read {} signatures", records.len());
+    let signatures: Vec<&[u32]> = records
+        .iter()
+        .map(|record| record.signature.as_slice())
+        .collect();
+    let clusters = find_duplicate_clusters(&signatures, bands, min_similarity)?;
+
+    let mut writer = BufWriter::new(
+        File::create(&output).with_context(|| format!("Failed to create {}", output.display()))?,
+    );
+    let mut dropped = 0;
+    for cluster in &clusters {
+        let kept = cluster
+            .iter()
+            .map(|&i| &records[i])
+            .max_by(|a, b| a.text_length.cmp(&b.text_length).then(b.id.cmp(&a.id)))
+            .expect("Clusters are not empty");
+        for &i in cluster {
+            let record = &records[i];
+            let decision = Decision {
+                id: record.id.clone(),
+                url: record.url.clone(),
+                keep: record.id == kept.id,
+                kept_id: kept.id.clone(),
+            };
+            dropped += usize::from(!decision.keep);
+            serde_json::to_writer(&mut writer, &decision)?;
+            writer.write_all(b"\n")?;
+        }
+    }
+    writer.flush()?;
+    tracing::info!(
+        "Found {} clusters of near-duplicates, {} documents are dropped",
+        clusters.len(),
+        dropped
+    );
+    Ok(())
+}
+
+/// The `[tokenizer]` section of a worker config. All other sections are ignored.
+#[derive(Debug, Default, Deserialize)]
+struct WorkerTokenizerConfig {
+    #[serde(default)]
+    tokenizer: TokenizerConfig,
+}
+
+/// Loads the tokenizer of the worker config, if one is configured in it.
+fn load_tokenizer(worker_config: &Path) -> Result<Option<DocumentTokenizer>, anyhow::Error> {
+    let content = std::fs::read_to_string(worker_config)
+        .with_context(|| format!("Failed to read worker config {}", worker_config.display()))?;
+    let config: WorkerTokenizerConfig = toml::from_str(&content)
+        .with_context(|| format!("Failed to parse worker config {}", worker_config.display()))?;
+    DocumentTokenizer::from_config(&config.tokenizer)
+}
+
+/// Returns the output shards below `dir` without the signature shards.
+fn document_shards(dir: &Path) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let signature_suffix = format!(".{}", SIGNATURE_SHARD_EXTENSION);
+    Ok(find_shards(dir, SHARD_EXTENSION)?
+        .into_iter()
+        .filter(|shard| !shard.to_string_lossy().ends_with(&signature_suffix))
+        .collect())
+}
+
+fn apply(decisions: PathBuf, input_dir: PathBuf, output_dir: PathBuf) -> Result<(), anyhow::Error> {
+    let file = File::open(&decisions)
+        .with_context(|| format!("Failed to open {}", decisions.display()))?;
+    let mut dropped_ids = HashSet::new();
+    for line in BufReader::new(file).lines() {
+        let decision: Decision = serde_json::from_str(&line?)?;
+        if !decision.keep {
+            dropped_ids.insert(decision.id);
+        }
+    }
+
+    let (mut kept, mut dropped) = (0, 0);
+    for shard in document_shards(&input_dir)? {
+        let records = read_shard::<OutputRecord>(&shard)?;
+        let total = records.len();
+        let keep: Vec<bool> = records
+            .iter()
+            .map(|record| !dropped_ids.contains(&record.id()))
+            .collect();
+        let records: Vec<_> = records
+            .into_iter()
+            .zip(&keep)
+            .filter_map(|(record, &keep)| keep.then_some(record))
+            .collect();
+        kept += records.len();
+        dropped += total - records.len();
+        let relative_path = shard.strip_prefix(&input_dir)?;
+        // The token shard of the output shard is copied without the token IDs of the dropped documents.
+        let token_shard = token_shard_path(&shard);
+        if has_token_shard(&token_shard) {
+            let documents = read_token_shard(&token_shard)?;
+            anyhow::ensure!(
+                documents.len() == total,
+                "Token shard {} does not match its output shard",
+                token_shard.display()
+            );
+            let documents: Vec<_> = documents
+                .iter()
+                .zip(&keep)
+                .filter_map(|(ids, &keep)| keep.then_some(ids.as_slice()))
+                .collect();
+            write_token_shard(
+                &token_shard_path(&output_dir.join(relative_path)),
+                documents.into_iter(),
+                &[],
+            )?;
+        }
+        write_shard(&output_dir.join(relative_path), &records)?;
+    }
+    tracing::info!("Kept {} documents and dropped {}", kept, dropped);
+    Ok(())
+}
+
+fn paragraphs(
+    input_dir: PathBuf,
+    output_dir: PathBuf,
+    max_occurrences: usize,
+    work_dir: PathBuf,
+    num_buckets: usize,
+    worker_config: Option<PathBuf>,
+) -> Result<(), anyhow::Error> {
+    let shards = document_shards(&input_dir)?;
+    let tokenizer = worker_config
+        .as_deref()
+        .map(load_tokenizer)
+        .transpose()?
+        .flatten();
+    if tokenizer.is_none() {
+        if let Some(shard) = shards
+            .iter()
+            .find(|shard| has_token_shard(&token_shard_path(shard)))
+        {
+            anyhow::bail!(
+                "{} has a token shard, pass --worker-config with a [tokenizer] section to tokenize the documents again",
+                shard.display()
+            );
+        }
+    }
+    let mut counter = ParagraphCounter::new(&work_dir, num_buckets)?;
+    for shard in &shards {
+        for record in read_shard::<OutputRecord>(shard)? {
+            counter.add_document(&record.document.text)?;
+        }
+    }
+    let frequent = counter.frequent_paragraphs(max_occurrences)?;
+    tracing::info!(
+        "Found {} paragraphs that occur in more than {} documents",
+        frequent.len(),
+        max_occurrences
+    );
+
+    let (mut kept_bytes, mut removed_bytes, mut dropped) = (0, 0, 0);
+    for shard in &shards {
+        let mut records = Vec::new();
+        for mut record in read_shard::<OutputRecord>(shard)? {
+            let old_length = record.document.text.len();
+            remove_frequent_paragraphs_from_record(&mut record, &frequent);
+            removed_bytes += old_length - record.document.text.len();
+            kept_bytes += record.document.text.len();
+            // Documents that consisted only of boilerplate are dropped.
+            if record.document.text.trim().is_empty() {
+                dropped += 1;
+                continue;
+            }
+            records.push(record);
+        }
+        let relative_path = shard.strip_prefix(&input_dir)?;
+        if let Some(tokenizer) = &tokenizer {
+            let texts: Vec<&str> = records
+                .iter()
+                .map(|record| record.document.text.as_str())
+                .collect();
+            let documents = tokenizer.encode(&texts)?;
+            for (record, ids) in records.iter_mut().zip(&documents) {
+                record.num_tokens = Some(ids.len());
+            }
+            if tokenizer.write_token_shards() {
+                tokenizer.write_token_shard(
+                    &token_shard_path(&output_dir.join(relative_path)),
+                    documents.iter().map(Vec::as_slice),
+                )?;
+            }
+        }
+        write_shard(&output_dir.join(relative_path), &records)?;
+    }
+    std::fs::remove_dir(&work_dir).ok();
+    tracing::info!(
+        "Removed {} of {} bytes of text and dropped {} empty documents",
+        removed_bytes,
+        kept_bytes + removed_bytes,
+        dropped
+    );
+    Ok(())
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+    setup_tracing();
+    match args.command {
+        Command::Cluster {
+            input_dir,
+            bands,
+            min_similarity,
+            output,
+        } => cluster(input_dir, bands, min_similarity, output),
+        Command::Apply {
+            decisions,
+            input_dir,
+            output_dir,
+        } => apply(decisions, input_dir, output_dir),
+        Command::Paragraphs {
+            input_dir,
+            output_dir,
+            max_occurrences,
+            work_dir,
+            num_buckets,
+            worker_config,
+        } => paragraphs(
+            input_dir,
+            output_dir,
+            max_occurrences,
+            work_dir,
+            num_buckets,
+            worker_config,
+        ),
+    }
+}
@@ -0,0 +1,69 @@
+//! Prepares the n-gram language models that the worker uses to compute the perplexity of documents.
+//!
+//! The models are trained with KenLM's `lmplz`, which is not part of this repository:
+//!
+//! 1. `prepare_lm normalize` normalizes a reference corpus, e.g. Wikipedia, in the same way as the worker
+//!    normalizes documents before scoring them, see [pipeline::perplexity::normalize_line].
+//! 2. `lmplz -o 5 < corpus.txt > model.arpa` trains the model.
+//! 3. `prepare_lm binarize` converts the ARPA file into a binary model that the worker loads much faster.
+//!
+//! Both the worker and `binarize` keep the whole model in memory, which takes about 24 bytes per 5-gram,
+//! i.e. about 12 GB for a 5-gram model with 500 million n-grams. Parsing an ARPA file whose n-grams are not sorted
+//! by the order of their words in the unigram section temporarily needs another 8 bytes per n-gram.
+use std::{
+    io::{BufRead, BufWriter, Write},
+    path::PathBuf,
+};
+
+use clap::{Parser, Subcommand};
+use pipeline::{
+    perplexity::{normalize_line, NgramModel},
+    tracing_and_metrics::setup_tracing,
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Normalizes the lines of stdin for training and writes them to stdout, skipping lines without words.
+    Normalize,
+    /// Converts an ARPA file, which may be gzip-compressed, into a binary model.
+    /// The model is kept in memory, which takes about 24 bytes per 5-gram.
+    Binarize {
+        /// The ARPA file.
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Path of the binary model that is written.
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    match Args::parse().command {
+        Command::Normalize => {
+            let mut stdout = BufWriter::new(std::io::stdout().lock());
+            for line in std::io::stdin().lock().lines() {
+                let normalized = normalize_line(&line?);
+                if !normalized.is_empty() {
+                    writeln!(stdout, "{}", normalized)?;
+                }
+            }
+            stdout.flush()?;
+        }
+        Command::Binarize { input, output } => {
+            // Tracing writes to stdout, which `normalize` uses for its output.
+            setup_tracing();
+            let model = NgramModel::load(&input)?;
+            model.save_binary(&output)?;
+            tracing::info!("Wrote {}-gram model to {}", model.order(), output.display());
+        }
+    }
+    Ok(())
+}
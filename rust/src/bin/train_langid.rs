@@ -0,0 +1,73 @@
+//! Trains the language model that the worker uses to identify the language of the extracted text.
+//!
+//! The input directory contains one plain text file per language, named after the language label
+//! that the model should predict, e.g. `eng.txt` and `deu.txt`. Use ISO 639-3 codes to match the cdx `languages` field.
+//! A few megabytes of text per language, e.g. from Wikipedia or Tatoeba, are enough for a reliable model.
+use std::path::PathBuf;
+
+use anyhow::Context;
+use clap::Parser;
+use pipeline::{
+    langid::{LanguageModel, DEFAULT_TEMPERATURE},
+    tracing_and_metrics::setup_tracing,
+};
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Directory with one `<language>.txt` file per language.
+    #[arg(short, long)]
+    input_dir: PathBuf,
+
+    /// Path of the JSON model file that is written.
+    #[arg(short, long, default_value = "langid.json")]
+    output: PathBuf,
+
+    /// Maximum number of characters per n-gram.
+    #[arg(long, default_value_t = 3)]
+    max_n: usize,
+
+    /// Number of most frequent n-grams that are kept per language.
+    #[arg(long, default_value_t = 5000)]
+    max_ngrams: usize,
+
+    /// Temperature of the softmax that computes the confidence, which is stored in the model.
+    /// Lower temperatures give clear-cut predictions a confidence closer to 1.
+    #[arg(long, default_value_t = DEFAULT_TEMPERATURE)]
+    temperature: f64,
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let args = Args::parse();
+    setup_tracing();
+    anyhow::ensure!(args.temperature > 0.0, "The temperature has to be positive");
+    let mut samples = Vec::new();
+    for entry in std::fs::read_dir(&args.input_dir)
+        .with_context(|| format!("Failed to read {}", args.input_dir.display()))?
+    {
+        let path = entry?.path();
+        if path.extension().is_none_or(|extension| extension != "txt") {
+            continue;
+        }
+        let language = path
+            .file_stem()
+            .context("Sample file has no name")?
+            .to_string_lossy()
+            .to_string();
+        let text = std::fs::read_to_string(&path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        tracing::info!("Read {} characters for language {}", text.len(), language);
+        samples.push((language, text));
+    }
+    let model = LanguageModel::train(
+        samples
+            .iter()
+            .map(|(language, text)| (language.as_str(), text.as_str())),
+        args.max_n,
+        args.max_ngrams,
+        args.temperature,
+    );
+    model.save(&args.output)?;
+    tracing::info!("Wrote language model to {}", args.output.display());
+    Ok(())
+}
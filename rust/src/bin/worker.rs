@@ -1,80 +1,464 @@
 //! The worker(s) pull(s) messages from the RabbitMQ queue and downloads the WARC files that contain the actual content of the URLs.
-//! Once the content has been downloaded, the worker extracts the text from the HTML file using the trafilatura Python package.
+//! Once the content has been downloaded, the worker extracts the text from the HTML file using the selected [Extractor] backend.
+//! By default, this is the trafilatura Python package, but a native Rust extractor can be selected with `--extractor readability`.
+//! With `--extractor trafilatura-subprocess`, trafilatura runs in a pool of subprocesses with per-document timeouts,
+//! so that a single pathological page cannot stall the worker.
 //!
-//! After having downloaded and extracted the text from the HTML file, the worker could apply some filters to the extracted text.
-//! We would also want to tokenize (for LLM training) the text and output it to a file.
+//! Every downloaded record runs through the document [Pipeline] of [pipeline::stages], whose stages and their order
+//! are configured in the `[pipeline]` section of the [WorkerConfig]. By default, the stages are the following.
+//! Before extraction, records that are too large, that are not HTML according to their `Content-Type` or
+//! `WARC-Identified-Payload-Type`, or whose payload does not look like HTML are skipped and counted per
+//! [pipeline::prefilter::Rejection] reason.
+//! After extraction, the text is cleaned by the stages in [pipeline::cleaning], e.g. boilerplate lines are removed.
+//! Then, the language of the cleaned text is identified with [pipeline::langid] and written to the output. Then, the quality filters from [pipeline::filters] are applied,
+//! including the bad words filter if lexicons are configured.
+//! Documents that are dropped by a cleaning stage, that are not in a target language or that fail a quality filter
+//! are either dropped or annotated with the failed rules, depending on the [pipeline::filters::FilterMode].
+//! If language models are configured, the perplexity of the text is computed with [pipeline::perplexity]
+//! and written to the output together with the quality tier of the document.
+//! If enabled, personal data such as email addresses and phone numbers is replaced with placeholders, see [pipeline::pii].
+//! The extracted text and metadata of every batch are written as one shard of [OutputRecord]s into `--output-dir`.
+//! With `--split-by-language`, every batch is written as one shard per language into a subdirectory named after the language,
+//! so that one run can produce datasets for several languages. The language is the identified language if the
+//! `language_id` stage runs, and the primary cdx language otherwise.
+//! With `--split-by-quality-tier`, the documents of every perplexity quality tier are written into a subdirectory
+//! named after the tier.
+//! If MinHash is enabled, a shard with the MinHash signatures of its documents is written next to every output shard,
+//! which the `dedup` binary uses to find near-duplicates.
+//! If a tokenizer is configured, the final text is tokenized and the number of tokens of every document is written
+//! to the output, and optionally a token shard with the token IDs of its documents is written next to every output shard,
+//! see [pipeline::tokenizer].
 //!
-//! In its current implementation it does not refine or filter the extracted text in any way nor does it output the extracted text to a file.
-use futures_util::StreamExt;
-use lapin::options::BasicAckOptions;
+//! Every entry of a batch is processed independently. If processing an entry fails, the failure is logged and counted
+//! per [RecordError] stage, and the worker continues with the next entry of the batch.
+//!
+//! The worker holds up to `--prefetch-count` batches at once and processes each of them in its own task.
+//! Every batch is acknowledged on its own once all of its entries have been processed.
+//! Within a batch, up to `--max-concurrent-downloads` WARC records are downloaded concurrently,
+//! and across all batches at most `--max-in-flight-records` records are downloaded or extracted at the same time.
+//! Parsing and text extraction are CPU-bound and run on tokio's blocking thread pool,
+//! bounded by `--max-concurrent-extractions`, so that they do not block the async runtime.
+//! Results are collected in the order of the entries in the batch.
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Context;
+use clap::Parser;
+use futures_util::{stream, StreamExt};
+use lapin::{
+    message::Delivery,
+    options::{BasicAckOptions, BasicNackOptions, BasicRejectOptions},
+};
+use lazy_static::lazy_static;
 use pipeline::{
     commoncrawl::{download_and_unzip, CdxEntry},
+    extractor::{new_extractor, ExtractorKind},
+    minhash::{MinHashConfig, MinHasher, SIGNATURE_SHARD_EXTENSION},
+    output::{shard_name, write_shard, OutputRecord, SHARD_EXTENSION, UNKNOWN_LANGUAGE},
+    prefilter::PrefilterConfig,
     rabbitmq::{
         rabbitmq_channel_with_queue, rabbitmq_connection, rabbitmq_consumer, CC_QUEUE_NAME,
     },
+    stages::{
+        count_rejection, Document, Pipeline, PipelineConfig, Stage, StageConfigs, StageError,
+    },
+    tokenizer::write_token_shard,
     tracing_and_metrics::{run_metrics_server, setup_tracing},
-    trafilatura,
+    trafilatura::TrafilaturaConfig,
+    trafilatura_pool::TrafilaturaPoolConfig,
 };
-use warc::WarcHeader;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+
+lazy_static! {
+    static ref FAILED_RECORDS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "worker_failed_records",
+        "Number of cdx entries whose processing failed, per failing stage",
+        &["stage"]
+    )
+    .unwrap();
+}
+
+/// The ways in which processing a single cdx entry can fail.
+#[derive(Debug)]
+enum RecordError {
+    Download(anyhow::Error),
+    /// Parsing the WARC record or a stage of the document pipeline failed.
+    Process(StageError),
+}
+
+impl From<StageError> for RecordError {
+    fn from(error: StageError) -> Self {
+        RecordError::Process(error)
+    }
+}
+
+impl RecordError {
+    /// The name of the failing stage, used as a metrics label.
+    fn stage(&self) -> &'static str {
+        match self {
+            RecordError::Download(_) => "download",
+            RecordError::Process(e) => e.stage,
+        }
+    }
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::Download(e) => write!(f, "download failed: {:#}", e),
+            RecordError::Process(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+#[derive(Parser, Debug)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Number of batches that the worker fetches from RabbitMQ and processes at the same time.
+    #[arg(long, default_value_t = 4)]
+    prefetch_count: u16,
+
+    /// Maximum number of records that are being downloaded or extracted at the same time, across all batches.
+    #[arg(long, default_value_t = 64)]
+    max_in_flight_records: usize,
+
+    /// Maximum number of WARC records that are downloaded concurrently within a batch.
+    #[arg(long, default_value_t = 16)]
+    max_concurrent_downloads: usize,
+
+    /// Maximum number of records that are parsed and extracted concurrently on the blocking thread pool.
+    /// Defaults to the number of available CPU cores.
+    #[arg(long)]
+    max_concurrent_extractions: Option<usize>,
+
+    /// The backend that is used to extract the text from HTML documents.
+    #[arg(long, value_enum, default_value_t)]
+    extractor: ExtractorKind,
+
+    /// Directory into which the worker writes one output shard per batch.
+    #[arg(short, long, default_value = "output")]
+    output_dir: PathBuf,
+
+    /// Write one shard per language and batch into `<output-dir>/<language>/`.
+    #[arg(long)]
+    split_by_language: bool,
+
+    /// Write the documents of every perplexity quality tier into `head/`, `middle/` and `tail/` subdirectories,
+    /// below the language directories if the output is split by language. Documents without a tier are not moved.
+    #[arg(long)]
+    split_by_quality_tier: bool,
+
+    /// Path to a TOML file with the [WorkerConfig]. See `worker.toml` for an example.
+    /// If not set, the defaults are used.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+}
+
+/// Configuration of the processing steps of the worker.
+/// Every section is optional and falls back to its defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct WorkerConfig {
+    /// Options for the trafilatura extractors.
+    trafilatura: TrafilaturaConfig,
+    /// Options for the subprocesses of the `trafilatura-subprocess` extractor.
+    trafilatura_pool: TrafilaturaPoolConfig,
+    /// The stages of the document pipeline and their order.
+    pipeline: PipelineConfig,
+    /// MinHash signatures for near-duplicate detection with the `dedup` binary.
+    minhash: MinHashConfig,
+    /// The sections of the stages.
+    #[serde(flatten)]
+    stages: StageConfigs,
+    /// All other sections, which are rejected when the config is parsed. `deny_unknown_fields` cannot be
+    /// combined with `flatten`.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+impl WorkerConfig {
+    fn load(path: Option<&PathBuf>) -> Result<Self, anyhow::Error> {
+        let Some(path) = path else {
+            return Ok(Self::default());
+        };
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read worker config {}", path.display()))?;
+        Self::parse(&content)
+            .with_context(|| format!("Failed to parse worker config {}", path.display()))
+    }
+
+    fn parse(content: &str) -> Result<Self, anyhow::Error> {
+        let config: Self = toml::from_str(content)?;
+        if let Some(section) = config.unknown.keys().next() {
+            anyhow::bail!("Unknown section `{}`", section);
+        }
+        Ok(config)
+    }
+}
+
+/// State that is shared by all batches that the worker processes at the same time.
+/// The semaphores limit the number of records in flight and the number of concurrent extractions across all batches.
+#[derive(Clone)]
+struct WorkerContext {
+    record_permits: Arc<Semaphore>,
+    extraction_permits: Arc<Semaphore>,
+    prefilter: Arc<PrefilterConfig>,
+    pipeline: Arc<Pipeline>,
+    output_dir: Arc<PathBuf>,
+    split_by_language: bool,
+    split_by_quality_tier: bool,
+    minhasher: Option<Arc<MinHasher>>,
+    /// Whether the token IDs that the `tokenize` stage kept are written to token shards.
+    token_shards: bool,
+}
+
+/// Downloads the WARC record of a cdx entry and runs the document pipeline on its `response` record.
+/// Holds a record permit for the whole duration and an extraction permit while the pipeline runs.
+/// Returns `Ok(None)` if the record contains no `response`, if it was rejected before download
+/// or if a stage of the pipeline dropped the document.
+async fn process_entry(
+    entry: &CdxEntry,
+    context: WorkerContext,
+) -> Result<Option<OutputRecord>, RecordError> {
+    if let Err(rejection) = context.prefilter.check_record_length(entry.metadata.length) {
+        count_rejection(context.prefilter.name(), rejection.as_str());
+        tracing::debug!(
+            url = entry.metadata.url,
+            reason = rejection.as_str(),
+            "Skipping record"
+        );
+        return Ok(None);
+    }
+    let _record_permit = context
+        .record_permits
+        .clone()
+        .acquire_owned()
+        .await
+        .expect("Record semaphore is never closed");
+    let data = download_and_unzip(
+        &format!("https://data.commoncrawl.org/{}", entry.metadata.filename),
+        entry.metadata.offset,
+        entry.metadata.length,
+    )
+    .await
+    .map_err(RecordError::Download)?;
+    let permit = context
+        .extraction_permits
+        .clone()
+        .acquire_owned()
+        .await
+        .expect("Extraction semaphore is never closed");
+    let entry = entry.clone();
+    let document = tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let Some(document) =
+            Document::from_warc_record(entry, &data, context.prefilter.max_payload_bytes)?
+        else {
+            return Ok(None);
+        };
+        context.pipeline.run(document)
+    })
+    .await
+    .map_err(|e| StageError::new("extract", e.into()))??;
+    Ok(document.and_then(Document::into_output_record))
+}
+
+/// Writes the output shard of a batch into `dir` and, if MinHash is enabled, its signature shard next to it.
+/// If token shards are enabled, the token IDs that the `tokenize` stage kept are written next to it as well.
+fn write_output(
+    dir: &Path,
+    name: &str,
+    records: &[OutputRecord],
+    minhasher: Option<&MinHasher>,
+    token_shards: bool,
+) -> Result<(), anyhow::Error> {
+    if token_shards {
+        // The `tokenize` stage already appended the EOS token.
+        write_token_shard(
+            &dir.join(name),
+            records
+                .iter()
+                .map(|record| record.token_ids.as_deref().unwrap_or_default()),
+            &[],
+        )?;
+    }
+    write_shard(&dir.join(format!("{}.{}", name, SHARD_EXTENSION)), records)?;
+    if let Some(minhasher) = minhasher {
+        let signatures: Vec<_> = records
+            .iter()
+            .filter_map(|record| minhasher.signature_record(record))
+            .collect();
+        write_shard(
+            &dir.join(format!("{}.{}", name, SIGNATURE_SHARD_EXTENSION)),
+            &signatures,
+        )?;
+    }
+    Ok(())
+}
+
+/// Processes all entries of a delivered batch, writes the output shard and acknowledges the delivery afterwards.
+/// Rejects the delivery without requeueing it if the batch cannot be deserialized or writing its output panicked,
+/// because both would happen again on every redelivery, and requeues it if the output shard cannot be written,
+/// e.g. because the disk is full.
+async fn process_delivery(
+    delivery: Delivery,
+    max_concurrent_downloads: usize,
+    context: WorkerContext,
+) {
+    let batch = match serde_json::from_slice::<Vec<CdxEntry>>(&delivery.data) {
+        Ok(batch) => batch,
+        Err(e) => {
+            tracing::error!(err.msg = %e, "Failed to deserialize batch. Rejecting it.");
+            if let Err(e) = delivery.reject(BasicRejectOptions { requeue: false }).await {
+                tracing::warn!(err.msg = %e, "Failed to reject batch");
+            }
+            return;
+        }
+    };
+    tracing::info!("Received a batch of {} entries", batch.len());
+    // Collecting the futures first keeps the closure out of the spawned future's type.
+    let futures: Vec<_> = batch
+        .iter()
+        .map(|entry| process_entry(entry, context.clone()))
+        .collect();
+    // `buffered` runs the futures concurrently but yields their results in batch order.
+    let results: Vec<_> = stream::iter(futures)
+        .buffered(max_concurrent_downloads)
+        .collect()
+        .await;
+    // Without `--split-by-language` and `--split-by-quality-tier`, all records go into one shard
+    // in the output directory, which is written even if it is empty.
+    let mut records_by_dir: BTreeMap<PathBuf, Vec<OutputRecord>> = BTreeMap::new();
+    if !context.split_by_language && !context.split_by_quality_tier {
+        records_by_dir.insert(context.output_dir.to_path_buf(), Vec::new());
+    }
+    for (entry, result) in batch.iter().zip(results) {
+        match result {
+            Ok(Some(record)) => {
+                let mut dir = context.output_dir.to_path_buf();
+                if context.split_by_language {
+                    dir.push(
+                        record
+                            .language
+                            .as_deref()
+                            .or(entry.metadata.primary_language())
+                            .unwrap_or(UNKNOWN_LANGUAGE),
+                    );
+                }
+                if let Some(tier) = record
+                    .quality_tier
+                    .filter(|_| context.split_by_quality_tier)
+                {
+                    dir.push(tier.as_str());
+                }
+                records_by_dir.entry(dir).or_default().push(record);
+            }
+            Ok(None) => {}
+            Err(e) => {
+                FAILED_RECORDS_COUNTER.with_label_values(&[e.stage()]).inc();
+                tracing::warn!(url = %entry.metadata.url, err.msg = %e, "Failed to process record");
+            }
+        }
+    }
+    if let Some(name) = shard_name(&batch) {
+        let minhasher = context.minhasher.clone();
+        let token_shards = context.token_shards;
+        let result = tokio::task::spawn_blocking(move || {
+            records_by_dir.iter().try_for_each(|(dir, records)| {
+                write_output(dir, &name, records, minhasher.as_deref(), token_shards)
+            })
+        })
+        .await;
+        let result = match result {
+            Ok(result) => result,
+            Err(e) => {
+                tracing::error!(err.msg = %e, "Writing the output shard panicked. Rejecting batch.");
+                if let Err(e) = delivery.reject(BasicRejectOptions { requeue: false }).await {
+                    tracing::warn!(err.msg = %e, "Failed to reject batch");
+                }
+                return;
+            }
+        };
+        if let Err(e) = result {
+            tracing::error!(err.msg = %e, "Failed to write output shard. Requeueing batch.");
+            let options = BasicNackOptions {
+                requeue: true,
+                ..Default::default()
+            };
+            if let Err(e) = delivery.nack(options).await {
+                tracing::warn!(err.msg = %e, "Failed to requeue batch");
+            }
+            return;
+        }
+    }
+    if let Err(e) = delivery.ack(BasicAckOptions::default()).await {
+        tracing::warn!(err.msg = %e, "Failed to acknowledge batch");
+    }
+}
 
 #[tokio::main]
 async fn main() {
+    let args = Args::parse();
     setup_tracing();
+    let config = WorkerConfig::load(args.config.as_ref()).unwrap();
+    tracing::info!("Using worker config {:?}", config);
     tokio::task::spawn(run_metrics_server(9001));
 
     let rabbit_conn = rabbitmq_connection().await.unwrap();
-    let (channel, _queue) = rabbitmq_channel_with_queue(&rabbit_conn, CC_QUEUE_NAME)
-        .await
-        .unwrap();
+    let (channel, _queue) =
+        rabbitmq_channel_with_queue(&rabbit_conn, CC_QUEUE_NAME, args.prefetch_count)
+            .await
+            .unwrap();
     let mut consumer = rabbitmq_consumer(&channel, CC_QUEUE_NAME, "worker")
         .await
         .unwrap();
+    let extractor = new_extractor(
+        args.extractor,
+        &config.trafilatura,
+        &config.trafilatura_pool,
+    )
+    .unwrap();
+    let pipeline = Pipeline::from_config(&config.pipeline, &config.stages, extractor).unwrap();
+    tracing::info!("Running pipeline stages {:?}", pipeline.stage_names());
+    let token_shards =
+        config.stages.tokenizer.write_token_shards && pipeline.stage_names().contains(&"tokenize");
+    let context = WorkerContext {
+        record_permits: Arc::new(Semaphore::new(args.max_in_flight_records)),
+        extraction_permits: Arc::new(Semaphore::new(
+            args.max_concurrent_extractions.unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(Into::into)
+                    .unwrap_or(1)
+            }),
+        )),
+        prefilter: Arc::new(config.stages.prefilter.clone()),
+        pipeline: Arc::new(pipeline),
+        output_dir: Arc::new(args.output_dir),
+        split_by_language: args.split_by_language,
+        split_by_quality_tier: args.split_by_quality_tier,
+        minhasher: config
+            .minhash
+            .enabled
+            .then(|| Arc::new(MinHasher::new(&config.minhash))),
+        token_shards,
+    };
+    // RabbitMQ delivers at most `prefetch_count` unacknowledged batches, which bounds the number of batch tasks.
     while let Some(delivery) = consumer.next().await {
         match delivery {
             Ok(delivery) => {
-                let batch = serde_json::from_slice::<Vec<CdxEntry>>(&delivery.data);
-                tracing::info!(
-                    "Received a batch of {} entries",
-                    batch.as_ref().unwrap().len()
-                );
-                for entry in batch.unwrap() {
-                    let data = download_and_unzip(
-                        &format!("https://data.commoncrawl.org/{}", entry.metadata.filename),
-                        entry.metadata.offset,
-                        entry.metadata.length,
-                    )
-                    .await
-                    .unwrap();
-                    for warc_entry in warc::WarcReader::new(data.as_slice()).iter_records() {
-                        let warc_entry = warc_entry.unwrap();
-                        if warc_entry.header(WarcHeader::WarcType).unwrap() != "response" {
-                            continue;
-                        }
-                        tracing::info!(
-                            "Successfully read WARC entry with URL {}",
-                            warc_entry.header(WarcHeader::TargetURI).unwrap()
-                        );
-                        let raw_content = String::from_utf8_lossy(warc_entry.body());
-                        let html_begin_index = raw_content.find("\n\n");
-                        let Some(html_begin_index) = html_begin_index else {
-                            tracing::warn!("Failed to find HTML content in WARC entry");
-                            continue;
-                        };
-                        tracing::debug!(
-                            "First 2000 characters of raw content: {}",
-                            &raw_content[..2000]
-                        );
-                        let content =
-                            trafilatura::extract(&raw_content[html_begin_index..]).unwrap();
-                        if let Some(content) = content {
-                            tracing::info!("Extracted content of length {}", content.len());
-                            tracing::debug!("Extracted content: {}", &content);
-                        } else {
-                            tracing::warn!("Failed to extract content from WARC entry");
-                        }
-                    }
-                }
-                delivery.ack(BasicAckOptions::default()).await.unwrap();
+                tokio::task::spawn(process_delivery(
+                    delivery,
+                    args.max_concurrent_downloads,
+                    context.clone(),
+                ));
             }
             Err(e) => {
                 tracing::warn!(err.msg = %e, err.details = ?e, "Failed to receive message from RabbitMQ. Reconnecting.");
@@ -83,3 +467,21 @@ async fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::WorkerConfig;
+
+    #[test]
+    fn can_parse_example_config() {
+        let config = WorkerConfig::parse(include_str!("../../worker.toml")).unwrap();
+        assert!(!config.trafilatura.include_tables);
+        assert!(config.stages.prefilter.sniff_html);
+    }
+
+    #[test]
+    fn rejects_unknown_sections() {
+        let err = WorkerConfig::parse("[text_cleanup]\nenabled = true\n").unwrap_err();
+        assert_eq!(err.to_string(), "Unknown section `text_cleanup`");
+    }
+}
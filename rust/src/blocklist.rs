@@ -0,0 +1,310 @@
+//! This module contains the URL blocklist that the batcher uses to exclude unwanted sites, e.g. adult or malware domains.
+//!
+//! A blocklist consists of one or more files. Every file belongs to a category, such as `adult` or `malware`,
+//! and contains one rule per line. Empty lines and lines starting with `#` are ignored. A rule is one of
+//!
+//! - `domain:example.com` blocks URLs whose host is exactly `example.com`,
+//! - `suffix:example.com` or just `example.com` blocks `example.com` and all its subdomains,
+//! - `prefix:example.com/forum/` blocks URLs that start with the prefix, ignoring the scheme,
+//! - `regex:^https?://[^/]*casino` blocks URLs that match the regular expression.
+//!
+//! Domain and suffix rules are stored in hash maps and prefix rules are grouped by host, so that the cost of a lookup
+//! does not depend on the number of rules, which can be millions. Only regex rules are matched one by one,
+//! as a single [RegexSet], and should therefore be used sparingly.
+use std::{
+    collections::HashMap,
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Context;
+use regex::RegexSet;
+
+/// A blocklist file and the category of its rules.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlocklistFile {
+    pub category: String,
+    pub path: PathBuf,
+}
+
+impl FromStr for BlocklistFile {
+    type Err = anyhow::Error;
+
+    /// Parses `<category>=<path>`, or just `<path>`, in which case the category is the file name without extension.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((category, path)) = s.split_once('=') {
+            return Ok(Self {
+                category: category.to_string(),
+                path: PathBuf::from(path),
+            });
+        }
+        let path = PathBuf::from(s);
+        let category = path
+            .file_stem()
+            .with_context(|| format!("Blocklist {} has no file name", s))?
+            .to_string_lossy()
+            .to_string();
+        Ok(Self { category, path })
+    }
+}
+
+/// The kind of a blocklist rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleKind {
+    Domain,
+    DomainSuffix,
+    UrlPrefix,
+    Regex,
+}
+
+impl RuleKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RuleKind::Domain => "domain",
+            RuleKind::DomainSuffix => "suffix",
+            RuleKind::UrlPrefix => "prefix",
+            RuleKind::Regex => "regex",
+        }
+    }
+}
+
+/// The category and kind of the rule that blocked a URL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlocklistMatch<'a> {
+    pub category: &'a str,
+    pub kind: RuleKind,
+}
+
+/// A set of blocklist rules, see the module documentation.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    categories: Vec<String>,
+    domains: HashMap<String, usize>,
+    domain_suffixes: HashMap<String, usize>,
+    /// Per host, the remainders of the prefixes after the host and their categories.
+    url_prefixes: HashMap<String, Vec<(String, usize)>>,
+    regexes: Vec<(String, usize)>,
+    regex_set: Option<RegexSet>,
+}
+
+impl Blocklist {
+    /// Loads the rules of all files.
+    pub fn load(files: &[BlocklistFile]) -> Result<Self, anyhow::Error> {
+        let mut blocklist = Self::default();
+        for file in files {
+            let content = std::fs::read_to_string(&file.path)
+                .with_context(|| format!("Failed to read blocklist {}", file.path.display()))?;
+            blocklist
+                .add_rules(&file.category, &content)
+                .with_context(|| format!("Failed to parse blocklist {}", file.path.display()))?;
+        }
+        blocklist.build()?;
+        Ok(blocklist)
+    }
+
+    /// Creates a blocklist from the rules of a single category.
+    pub fn parse(category: &str, rules: &str) -> Result<Self, anyhow::Error> {
+        let mut blocklist = Self::default();
+        blocklist.add_rules(category, rules)?;
+        blocklist.build()?;
+        Ok(blocklist)
+    }
+
+    fn add_rules(&mut self, category: &str, rules: &str) -> Result<(), anyhow::Error> {
+        let category_id = match self.categories.iter().position(|c| c == category) {
+            Some(id) => id,
+            None => {
+                self.categories.push(category.to_string());
+                self.categories.len() - 1
+            }
+        };
+        for rule in rules.lines().map(str::trim) {
+            if rule.is_empty() || rule.starts_with('#') {
+                continue;
+            }
+            let (kind, value) = rule.split_once(':').unwrap_or(("suffix", rule));
+            match kind {
+                "domain" => {
+                    self.domains.insert(normalize_host(value), category_id);
+                }
+                "suffix" => {
+                    self.domain_suffixes
+                        .insert(normalize_host(value), category_id);
+                }
+                "prefix" => {
+                    let (host, rest) = split_url(value);
+                    self.url_prefixes
+                        .entry(host)
+                        .or_default()
+                        .push((rest.to_string(), category_id));
+                }
+                "regex" => self.regexes.push((value.to_string(), category_id)),
+                _ => anyhow::bail!("Unknown blocklist rule {}", rule),
+            }
+        }
+        Ok(())
+    }
+
+    fn build(&mut self) -> Result<(), anyhow::Error> {
+        if !self.regexes.is_empty() {
+            self.regex_set = Some(RegexSet::new(self.regexes.iter().map(|(regex, _)| regex))?);
+        }
+        Ok(())
+    }
+
+    /// The total number of rules.
+    pub fn len(&self) -> usize {
+        self.domains.len()
+            + self.domain_suffixes.len()
+            + self.url_prefixes.values().map(Vec::len).sum::<usize>()
+            + self.regexes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the first rule that blocks `url`, checking domain, suffix, prefix and regex rules in this order.
+    pub fn matches(&self, url: &str) -> Option<BlocklistMatch<'_>> {
+        let found = |category: usize, kind| {
+            Some(BlocklistMatch {
+                category: &self.categories[category],
+                kind,
+            })
+        };
+        let (host, rest) = split_url(url);
+        if let Some(&category) = self.domains.get(&host) {
+            return found(category, RuleKind::Domain);
+        }
+        let mut suffix = host.as_str();
+        loop {
+            if let Some(&category) = self.domain_suffixes.get(suffix) {
+                return found(category, RuleKind::DomainSuffix);
+            }
+            match suffix.split_once('.') {
+                Some((_, parent)) => suffix = parent,
+                None => break,
+            }
+        }
+        if let Some(prefixes) = self.url_prefixes.get(&host) {
+            if let Some((_, category)) =
+                prefixes.iter().find(|(prefix, _)| rest.starts_with(prefix))
+            {
+                return found(*category, RuleKind::UrlPrefix);
+            }
+        }
+        let regex = self.regex_set.as_ref()?.matches(url).into_iter().next()?;
+        found(self.regexes[regex].1, RuleKind::Regex)
+    }
+}
+
+fn normalize_host(host: &str) -> String {
+    host.trim_start_matches("*.")
+        .trim_start_matches('.')
+        .trim_end_matches('.')
+        .to_lowercase()
+}
+
+/// Splits a URL into its normalized host and the remainder after the host, e.g. the path and query.
+/// The scheme, user info and port are removed.
+fn split_url(url: &str) -> (String, &str) {
+    let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let end = without_scheme
+        .find(['/', '?', '#'])
+        .unwrap_or(without_scheme.len());
+    let (authority, rest) = without_scheme.split_at(end);
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    let host = host.split(':').next().unwrap_or_default();
+    (normalize_host(host), rest)
+}
+
+fn modification_times(files: &[BlocklistFile]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| {
+            std::fs::metadata(&file.path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+        })
+        .collect()
+}
+
+/// Starts a thread that reloads the blocklist whenever one of its files changes, checking every `interval`.
+/// If reloading fails, e.g. because a file is only partially written, the previous rules stay active.
+pub fn reload_on_change(
+    blocklist: Arc<RwLock<Blocklist>>,
+    files: Vec<BlocklistFile>,
+    interval: Duration,
+) {
+    std::thread::spawn(move || {
+        let mut modified = modification_times(&files);
+        loop {
+            std::thread::sleep(interval);
+            let current = modification_times(&files);
+            if current == modified {
+                continue;
+            }
+            match Blocklist::load(&files) {
+                Ok(reloaded) => {
+                    tracing::info!("Reloaded blocklist with {} rules", reloaded.len());
+                    *blocklist.write().unwrap() = reloaded;
+                    modified = current;
+                }
+                Err(e) => {
+                    tracing::warn!(err.msg = %e, "Failed to reload blocklist, keeping the previous rules");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Blocklist, BlocklistFile, RuleKind};
+
+    #[test]
+    fn blocks_urls() {
+        let blocklist = Blocklist::parse(
+            "adult",
+            "# Example rules\n\
+             example.com\n\
+             domain:exact.org\n\
+             prefix:https://forum.net/private/\n\
+             regex:^https?://[^/]*casino",
+        )
+        .unwrap();
+        assert_eq!(blocklist.len(), 4);
+        let kind = |url| blocklist.matches(url).map(|m| m.kind);
+        assert_eq!(
+            kind("https://www.Example.com/page"),
+            Some(RuleKind::DomainSuffix)
+        );
+        assert_eq!(
+            kind("http://user@example.com:8080/"),
+            Some(RuleKind::DomainSuffix)
+        );
+        assert_eq!(kind("https://notexample.com/"), None);
+        assert_eq!(kind("https://exact.org/a"), Some(RuleKind::Domain));
+        assert_eq!(kind("https://sub.exact.org/a"), None);
+        assert_eq!(
+            kind("http://forum.net/private/thread/1"),
+            Some(RuleKind::UrlPrefix)
+        );
+        assert_eq!(kind("http://forum.net/public/"), None);
+        assert_eq!(kind("https://best-casino.io/"), Some(RuleKind::Regex));
+        assert_eq!(
+            blocklist.matches("https://exact.org").unwrap().category,
+            "adult"
+        );
+        assert!(Blocklist::parse("adult", "ftp:example.com").is_err());
+
+        let file: BlocklistFile = "lists/malware.txt".parse().unwrap();
+        assert_eq!(file.category, "malware");
+        let file: BlocklistFile = "copyright=lists/dmca.txt".parse().unwrap();
+        assert_eq!(file.category, "copyright");
+    }
+}
@@ -0,0 +1,181 @@
+//! This module contains a Bloom filter that the batcher uses to skip pages whose content it has already seen.
+//!
+//! A Bloom filter stores a set in a fixed number of bits, independent of the number of inserted items.
+//! It never misses an item that was inserted, but reports an item that was not inserted with a small,
+//! configurable false positive rate. The filter can be saved to disk and loaded again, so that
+//! deduplication works across several runs of the batcher.
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+
+use anyhow::Context;
+use twox_hash::XxHash64;
+
+/// The first bytes of a saved Bloom filter, which identify the file format.
+const MAGIC: &[u8; 8] = b"PLBLOOM1";
+
+/// The length of the magic bytes, `num_bits` and `num_hashes` at the beginning of a saved filter.
+const HEADER_BYTES: u64 = 8 + 8 + 4;
+
+/// More hash functions than this are never optimal, even for false positive rates far below any practical use.
+const MAX_HASHES: u32 = 64;
+
+/// A Bloom filter over byte strings. The bit positions are derived from two xxHash64 hashes of
+/// the item with double hashing, which is stable across platforms and Rust versions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    num_bits: u64,
+    num_hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates an empty filter that has the given false positive rate once `expected_items` have been inserted.
+    pub fn new(expected_items: usize, false_positive_rate: f64) -> Self {
+        let expected_items = expected_items.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let num_bits = (-expected_items * false_positive_rate.ln() / (ln2 * ln2))
+            .ceil()
+            .max(64.0) as u64;
+        let num_hashes = ((num_bits as f64 / expected_items) * ln2).round().max(1.0) as u32;
+        Self {
+            bits: vec![0; num_bits.div_ceil(64) as usize],
+            num_bits,
+            num_hashes,
+        }
+    }
+
+    /// The number of bytes that the bits of the filter use.
+    pub fn size_in_bytes(&self) -> usize {
+        self.bits.len() * 8
+    }
+
+    fn bit_indices(&self, item: &[u8]) -> impl Iterator<Item = u64> {
+        let h1 = XxHash64::oneshot(0, item);
+        let h2 = XxHash64::oneshot(1, item) | 1;
+        let num_bits = self.num_bits;
+        (0..self.num_hashes as u64).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % num_bits)
+    }
+
+    /// Whether the item has (probably) been inserted before.
+    pub fn contains(&self, item: &[u8]) -> bool {
+        self.bit_indices(item)
+            .all(|i| self.bits[(i / 64) as usize] & (1 << (i % 64)) != 0)
+    }
+
+    /// Inserts the item and returns whether it has (probably) been inserted before.
+    pub fn insert(&mut self, item: &[u8]) -> bool {
+        let indices: Vec<u64> = self.bit_indices(item).collect();
+        let mut present = true;
+        for i in indices {
+            let word = &mut self.bits[(i / 64) as usize];
+            let mask = 1 << (i % 64);
+            present &= *word & mask != 0;
+            *word |= mask;
+        }
+        present
+    }
+
+    /// Writes the filter to `path`. The filter is first written to a temporary file which is renamed afterwards,
+    /// so that an interrupted save does not destroy the previous state.
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let tmp_path = path.with_extension("tmp");
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&self.num_bits.to_le_bytes())?;
+        writer.write_all(&self.num_hashes.to_le_bytes())?;
+        for word in &self.bits {
+            writer.write_all(&word.to_le_bytes())?;
+        }
+        writer.flush()?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("Failed to move Bloom filter to {}", path.display()))?;
+        Ok(())
+    }
+
+    /// Reads a filter that was written with [BloomFilter::save].
+    /// Fails if the header is implausible or if the length of the file does not match the number of bits.
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let file_length = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        anyhow::ensure!(&magic == MAGIC, "{} is not a Bloom filter", path.display());
+        let mut num_bits = [0u8; 8];
+        reader.read_exact(&mut num_bits)?;
+        let num_bits = u64::from_le_bytes(num_bits);
+        let mut num_hashes = [0u8; 4];
+        reader.read_exact(&mut num_hashes)?;
+        let num_hashes = u32::from_le_bytes(num_hashes);
+        anyhow::ensure!(
+            num_bits > 0 && (1..=MAX_HASHES).contains(&num_hashes),
+            "Bloom filter {} has {} bits and {} hash functions",
+            path.display(),
+            num_bits,
+            num_hashes
+        );
+        anyhow::ensure!(
+            num_bits.div_ceil(64).checked_mul(8) == file_length.checked_sub(HEADER_BYTES),
+            "Bloom filter {} with {} bits has an unexpected length of {} bytes",
+            path.display(),
+            num_bits,
+            file_length
+        );
+        let mut bits = vec![0u64; num_bits.div_ceil(64) as usize];
+        let mut word = [0u8; 8];
+        for bits in bits.iter_mut() {
+            reader
+                .read_exact(&mut word)
+                .with_context(|| format!("Bloom filter {} is truncated", path.display()))?;
+            *bits = u64::from_le_bytes(word);
+        }
+        Ok(Self {
+            bits,
+            num_bits,
+            num_hashes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BloomFilter;
+
+    #[test]
+    fn can_insert_save_and_load() {
+        let mut filter = BloomFilter::new(1000, 0.01);
+        assert!(!filter.insert(b"5JOQMMSNM6N7UCLGGYXDSPSB3FYAQS2C"));
+        assert!(filter.insert(b"5JOQMMSNM6N7UCLGGYXDSPSB3FYAQS2C"));
+        assert!(!filter.contains(b"DCNYNIFG5SBRCVS5PCUY4YY2UM2WAQ4R"));
+
+        let false_positives = (0..1000)
+            .filter(|i| filter.insert(format!("digest-{}", i).as_bytes()))
+            .count();
+        assert!(false_positives < 30);
+
+        let path = std::env::temp_dir().join(format!("bloom-test-{}", std::process::id()));
+        filter.save(&path).unwrap();
+        assert_eq!(BloomFilter::load(&path).unwrap(), filter);
+
+        let saved = std::fs::read(&path).unwrap();
+        let corrupt = |offset: usize, bytes: &[u8]| {
+            let mut corrupted = saved.clone();
+            corrupted[offset..offset + bytes.len()].copy_from_slice(bytes);
+            std::fs::write(&path, corrupted).unwrap();
+            BloomFilter::load(&path)
+        };
+        assert!(corrupt(8, &0u64.to_le_bytes()).is_err());
+        assert!(corrupt(8, &u64::MAX.to_le_bytes()).is_err());
+        assert!(corrupt(16, &0u32.to_le_bytes()).is_err());
+        assert!(corrupt(16, &1000u32.to_le_bytes()).is_err());
+        std::fs::write(&path, &saved[..saved.len() - 8]).unwrap();
+        assert!(BloomFilter::load(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
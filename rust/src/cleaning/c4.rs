@@ -0,0 +1,192 @@
+//! The line-level cleaning rules that were used to build the C4 dataset (Raffel et al., 2020, Section 2.2).
+//!
+//! Lines are removed if they
+//!
+//! - contain a word with more than 1000 characters,
+//! - do not end with terminal punctuation or end with an ellipsis,
+//! - have fewer than 3 words,
+//! - contain the word "javascript", or
+//! - contain boilerplate about cookies or policies, such as "privacy policy".
+//!
+//! The whole document is dropped if a remaining line contains "lorem ipsum" or a curly brace,
+//! which indicates placeholder text or source code, or if fewer than 5 sentences remain after cleaning.
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+
+use super::TextCleaner;
+use crate::filters::{words, FilterFailure};
+
+lazy_static! {
+    static ref REMOVED_LINES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "c4_removed_lines",
+        "Number of lines that the C4 cleaning rules removed, per rule",
+        &["rule"]
+    )
+    .unwrap();
+}
+
+/// Characters with which a line has to end to be kept.
+const TERMINAL_PUNCTUATION: &[char] = &['.', '!', '?', '"', '”', '。', '！', '？'];
+
+/// Lines that end with an ellipsis are usually teasers or truncated, so they are removed as well.
+const ELLIPSES: &[&str] = &["...", "…"];
+
+/// Substrings of lines that are boilerplate about cookies or policies. They are compared in lower case.
+const POLICY_SUBSTRINGS: &[&str] = &[
+    "terms of use",
+    "privacy policy",
+    "cookie policy",
+    "uses cookies",
+    "use of cookies",
+    "use cookies",
+];
+
+/// Thresholds and switches of the C4 cleaning rules.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct C4CleaningConfig {
+    pub enabled: bool,
+    /// Lines that contain a longer word are removed.
+    pub max_word_length: usize,
+    pub remove_no_terminal_punctuation: bool,
+    /// Lines with fewer words are removed.
+    pub min_words_per_line: usize,
+    pub remove_javascript: bool,
+    pub remove_policy: bool,
+    pub drop_lorem_ipsum: bool,
+    pub drop_curly_braces: bool,
+    /// Documents with fewer sentences after cleaning are dropped.
+    pub min_sentences: usize,
+}
+
+impl Default for C4CleaningConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            max_word_length: 1000,
+            remove_no_terminal_punctuation: true,
+            min_words_per_line: 3,
+            remove_javascript: true,
+            remove_policy: true,
+            drop_lorem_ipsum: true,
+            drop_curly_braces: true,
+            min_sentences: 5,
+        }
+    }
+}
+
+impl C4CleaningConfig {
+    /// The rule for which a line is removed, if any.
+    /// Returns `Err` with the rule if the whole document has to be dropped because of this line.
+    fn line_rule(&self, line: &str) -> Result<Option<&'static str>, &'static str> {
+        let words: Vec<&str> = words(line).collect();
+        if words
+            .iter()
+            .any(|word| word.chars().count() > self.max_word_length)
+        {
+            return Ok(Some("max_word_length"));
+        }
+        if self.remove_no_terminal_punctuation
+            && (!line.ends_with(TERMINAL_PUNCTUATION) || ELLIPSES.iter().any(|e| line.ends_with(e)))
+        {
+            return Ok(Some("no_terminal_punctuation"));
+        }
+        if words.len() < self.min_words_per_line {
+            return Ok(Some("min_words_per_line"));
+        }
+        let lowercase = line.to_lowercase();
+        if self.drop_lorem_ipsum && lowercase.contains("lorem ipsum") {
+            return Err("lorem_ipsum");
+        }
+        if self.remove_javascript && lowercase.contains("javascript") {
+            return Ok(Some("javascript"));
+        }
+        if self.drop_curly_braces && line.contains('{') {
+            return Err("curly_braces");
+        }
+        if self.remove_policy && POLICY_SUBSTRINGS.iter().any(|s| lowercase.contains(s)) {
+            return Ok(Some("policy"));
+        }
+        Ok(None)
+    }
+}
+
+impl TextCleaner for C4CleaningConfig {
+    fn clean(&self, text: &str) -> Result<String, FilterFailure> {
+        let failure = |rule| FilterFailure { filter: "c4", rule };
+        let mut kept = Vec::new();
+        for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            match self.line_rule(line).map_err(failure)? {
+                Some(rule) => REMOVED_LINES_COUNTER.with_label_values(&[rule]).inc(),
+                None => kept.push(line),
+            }
+        }
+        if kept.iter().map(|line| count_sentences(line)).sum::<usize>() < self.min_sentences {
+            return Err(failure("min_sentences"));
+        }
+        Ok(kept.join("\n"))
+    }
+}
+
+/// Counts the sentences of a line as the number of terminal punctuation marks that are
+/// followed by whitespace or the end of the line. Repeated marks such as `?!` count once.
+fn count_sentences(line: &str) -> usize {
+    let mut chars = line.chars().peekable();
+    let mut sentences = 0;
+    while let Some(c) = chars.next() {
+        if TERMINAL_PUNCTUATION.contains(&c) && chars.peek().is_none_or(|next| next.is_whitespace())
+        {
+            sentences += 1;
+        }
+    }
+    sentences
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count_sentences, C4CleaningConfig};
+    use crate::{cleaning::TextCleaner, filters::FilterFailure};
+
+    fn drop_rule(text: &str) -> Option<&'static str> {
+        C4CleaningConfig::default()
+            .clean(text)
+            .err()
+            .map(|FilterFailure { rule, .. }| rule)
+    }
+
+    #[test]
+    fn removes_boilerplate_lines() {
+        let cleaned = C4CleaningConfig::default()
+            .clean(include_str!("../../tests/fixtures/c4/article.txt"))
+            .unwrap();
+        assert_eq!(
+            cleaned,
+            include_str!("../../tests/fixtures/c4/article.expected.txt").trim_end()
+        );
+    }
+
+    #[test]
+    fn drops_documents() {
+        assert_eq!(
+            drop_rule(include_str!("../../tests/fixtures/c4/lorem_ipsum.txt")),
+            Some("lorem_ipsum")
+        );
+        assert_eq!(
+            drop_rule(include_str!("../../tests/fixtures/c4/code.txt")),
+            Some("curly_braces")
+        );
+        assert_eq!(
+            drop_rule(include_str!("../../tests/fixtures/c4/short.txt")),
+            Some("min_sentences")
+        );
+    }
+
+    #[test]
+    fn counts_sentences() {
+        assert_eq!(
+            count_sentences("One. Two?! Three... Version 1.2 is out."),
+            4
+        );
+    }
+}
@@ -0,0 +1,57 @@
+//! This module contains the stages that clean the extracted text before the quality filters run.
+//!
+//! Every stage implements [TextCleaner]. It either returns a cleaned version of the text, e.g. with
+//! boilerplate lines removed, or the rule for which the whole document should be dropped.
+//! The [TextCleaningPipeline] runs the enabled stages one after another.
+use serde::{Deserialize, Serialize};
+
+use crate::filters::FilterFailure;
+
+pub mod c4;
+
+use c4::C4CleaningConfig;
+
+/// A stage that cleans extracted text.
+pub trait TextCleaner: Send + Sync {
+    /// Returns the cleaned text, or the rule for which the document should be dropped.
+    fn clean(&self, text: &str) -> Result<String, FilterFailure>;
+}
+
+/// Configuration of all text cleaning stages.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TextCleaningConfig {
+    /// The line-level rules from the C4 dataset.
+    pub c4: C4CleaningConfig,
+}
+
+/// Runs a sequence of [TextCleaner]s.
+pub struct TextCleaningPipeline {
+    cleaners: Vec<Box<dyn TextCleaner>>,
+}
+
+impl TextCleaningPipeline {
+    pub fn new(cleaners: Vec<Box<dyn TextCleaner>>) -> Self {
+        Self { cleaners }
+    }
+
+    /// Creates the pipeline with all stages that are enabled in the config.
+    pub fn from_config(config: &TextCleaningConfig) -> Self {
+        let mut cleaners: Vec<Box<dyn TextCleaner>> = Vec::new();
+        if config.c4.enabled {
+            cleaners.push(Box::new(config.c4.clone()));
+        }
+        Self::new(cleaners)
+    }
+
+    /// Runs all stages on `text` and stops at the first stage that drops the document.
+    /// The rule is counted by the [crate::stages::Pipeline] in the `quality_filter_failures` metric,
+    /// together with whether the document was dropped or annotated.
+    pub fn clean(&self, text: &str) -> Result<String, FilterFailure> {
+        let mut text = text.to_string();
+        for cleaner in &self.cleaners {
+            text = cleaner.clean(&text)?;
+        }
+        Ok(text)
+    }
+}
@@ -1,6 +1,8 @@
 //! This module contains helper functions and structs for de-serializing CommonCrawl-specific data structures.
 use std::io::Read;
 
+use anyhow::Context;
+use clap::ValueEnum;
 use lazy_static::lazy_static;
 use prometheus::{register_int_counter, IntCounter};
 use serde::{Deserialize, Serialize};
@@ -16,7 +18,7 @@ lazy_static! {
 
 /// Metadata for a crawled URL.
 /// We use this metadata in the batcher to filter URLs before passing them on to the worker(s).
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CdxMetadata {
     pub url: String,
     #[serde(deserialize_with = "deserialize_number_from_string")]
@@ -27,6 +29,48 @@ pub struct CdxMetadata {
     pub offset: usize,
     pub filename: String,
     pub languages: Option<String>,
+    pub charset: Option<String>,
+    /// The SHA-1 digest of the payload in base32, which is identical for pages with identical content.
+    pub digest: Option<String>,
+}
+
+/// How the languages of a cdx entry are matched against the target languages.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum LanguageMatch {
+    /// The primary language of the page, i.e. the first entry in `languages`, is a target language.
+    Primary,
+    /// Any of the languages of the page is a target language.
+    #[default]
+    Any,
+}
+
+impl CdxMetadata {
+    /// The languages of the page as ISO 639-3 codes, ordered by their share of the page.
+    pub fn languages(&self) -> impl Iterator<Item = &str> {
+        self.languages
+            .as_deref()
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|language| !language.is_empty())
+    }
+
+    /// The language with the largest share of the page, if known.
+    pub fn primary_language(&self) -> Option<&str> {
+        self.languages().next()
+    }
+
+    /// Whether the page is in one of the target languages. Pages without languages never match.
+    pub fn matches_languages(&self, targets: &[String], mode: LanguageMatch) -> bool {
+        match mode {
+            LanguageMatch::Primary => self
+                .primary_language()
+                .is_some_and(|language| targets.iter().any(|target| target == language)),
+            LanguageMatch::Any => self
+                .languages()
+                .any(|language| targets.iter().any(|target| target == language)),
+        }
+    }
 }
 
 /// Downloads a given byte range from a URL and unzips the resulting data into a byte Vec.
@@ -42,10 +86,13 @@ pub async fn download_and_unzip(
         .header("Range", format!("bytes={}-{}", offset, offset + length - 1))
         .send()
         .await
-        .unwrap();
+        .with_context(|| format!("Failed to send request for {}", url))?;
     match res.status() {
         reqwest::StatusCode::PARTIAL_CONTENT => {
-            let body = res.bytes().await.unwrap();
+            let body = res
+                .bytes()
+                .await
+                .with_context(|| format!("Failed to read response body of {}", url))?;
             tracing::info!(
                 "Successfully fetched the URL {} from {} to {}",
                 url,
@@ -55,7 +102,9 @@ pub async fn download_and_unzip(
             DOWNLOADED_BYTES_COUNTER.inc_by(body.len() as u64);
             let mut decoder = flate2::read::GzDecoder::new(&body[..]);
             let mut buffer = Vec::new();
-            decoder.read_to_end(&mut buffer).unwrap();
+            decoder
+                .read_to_end(&mut buffer)
+                .with_context(|| format!("Failed to unzip response body of {}", url))?;
             Ok(buffer)
         }
         _ => Err(anyhow::anyhow!(
@@ -67,7 +116,7 @@ pub async fn download_and_unzip(
 }
 
 /// Represents a line in a cdx index file.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CdxEntry {
     pub surt_url: String,
     pub timestamp: String,
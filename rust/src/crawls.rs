@@ -0,0 +1,205 @@
+//! This module merges the cdx indices of several crawls into a single stream with one capture per URL.
+//!
+//! Crawls overlap heavily, so processing several crawls independently would download most pages several times.
+//! The lines of every cdx index are sorted by SURT URL, which is the canonical form of the URL.
+//! [MergedCdxIndex] therefore merges the indices like the merge step of merge sort and only
+//! needs to buffer the current cdx chunk of every crawl. Of all captures of a URL, it emits the one
+//! with the highest crawl priority and, among those, the one with the newest timestamp.
+use std::collections::VecDeque;
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+
+use crate::commoncrawl::{download_and_unzip, parse_cdx_line, CdxEntry, ClusterIdxEntry};
+
+lazy_static! {
+    static ref SUPERSEDED_CAPTURES_COUNTER: IntCounter = register_int_counter!(
+        "batcher_superseded_captures",
+        "Number of cdx entries that were skipped because a newer or higher priority capture of their URL exists"
+    )
+    .unwrap();
+}
+
+/// The crawl that is processed if no other crawl is given.
+pub const DEFAULT_CRAWL: &str = "CC-MAIN-2024-30";
+
+/// Returns the URL of a cdx index file of a crawl.
+pub fn cdx_file_url(crawl: &str, cdx_filename: &str) -> String {
+    format!(
+        "https://data.commoncrawl.org/cc-index/collections/{}/indexes/{}",
+        crawl, cdx_filename
+    )
+}
+
+/// The cdx index of a single crawl, which is downloaded chunk by chunk.
+pub struct CrawlIndex {
+    pub crawl: String,
+    /// Captures of crawls with a higher priority win over newer captures of crawls with a lower priority.
+    pub priority: i64,
+    chunks: VecDeque<ClusterIdxEntry>,
+    entries: VecDeque<CdxEntry>,
+}
+
+impl CrawlIndex {
+    /// Creates the index of a crawl from the chunks of its cluster.idx file.
+    pub fn new(crawl: String, priority: i64, chunks: Vec<ClusterIdxEntry>) -> Self {
+        Self {
+            crawl,
+            priority,
+            chunks: chunks.into(),
+            entries: VecDeque::new(),
+        }
+    }
+
+    /// Downloads the next chunk and buffers its entries for which `keep` returns true.
+    /// Returns false if all chunks have been downloaded.
+    async fn download_next_chunk(
+        &mut self,
+        keep: &impl Fn(&CdxEntry) -> bool,
+    ) -> Result<bool, anyhow::Error> {
+        let Some(chunk) = self.chunks.pop_front() else {
+            return Ok(false);
+        };
+        let content = download_and_unzip(
+            &cdx_file_url(&self.crawl, &chunk.cdx_filename),
+            chunk.cdx_offset,
+            chunk.cdx_length,
+        )
+        .await?;
+        self.entries.extend(
+            String::from_utf8(content)?
+                .lines()
+                .map(parse_cdx_line)
+                .filter(|entry| keep(entry)),
+        );
+        Ok(true)
+    }
+}
+
+/// Merges the cdx indices of several crawls, see the module documentation.
+pub struct MergedCdxIndex<F> {
+    crawls: Vec<CrawlIndex>,
+    keep: F,
+    num_chunks_downloaded: usize,
+}
+
+impl<F: Fn(&CdxEntry) -> bool> MergedCdxIndex<F> {
+    /// Creates the merged index. Only entries for which `keep` returns true are considered,
+    /// so that an older capture wins if the newest capture of a URL is, for example, an error page.
+    pub fn new(crawls: Vec<CrawlIndex>, keep: F) -> Self {
+        Self {
+            crawls,
+            keep,
+            num_chunks_downloaded: 0,
+        }
+    }
+
+    /// The number of cdx chunks that have been downloaded across all crawls.
+    pub fn num_chunks_downloaded(&self) -> usize {
+        self.num_chunks_downloaded
+    }
+
+    /// Returns the capture of the next URL in SURT order, or `None` if all indices have been processed.
+    pub async fn next(&mut self) -> Result<Option<CdxEntry>, anyhow::Error> {
+        for crawl in &mut self.crawls {
+            while crawl.entries.is_empty() && crawl.download_next_chunk(&self.keep).await? {
+                self.num_chunks_downloaded += 1;
+            }
+        }
+        let Some(surt_url) = self
+            .crawls
+            .iter()
+            .filter_map(|crawl| crawl.entries.front())
+            .map(|entry| &entry.surt_url)
+            .min()
+            .cloned()
+        else {
+            return Ok(None);
+        };
+        // The captures of a URL can continue in the next chunk of a crawl.
+        for crawl in &mut self.crawls {
+            while crawl
+                .entries
+                .back()
+                .is_some_and(|entry| entry.surt_url == surt_url)
+                && crawl.download_next_chunk(&self.keep).await?
+            {
+                self.num_chunks_downloaded += 1;
+            }
+        }
+        Ok(pop_preferred_capture(&mut self.crawls, &surt_url))
+    }
+}
+
+/// Removes all buffered captures of `surt_url` and returns the preferred one.
+fn pop_preferred_capture(crawls: &mut [CrawlIndex], surt_url: &str) -> Option<CdxEntry> {
+    let mut preferred: Option<(i64, CdxEntry)> = None;
+    for crawl in crawls {
+        while crawl
+            .entries
+            .front()
+            .is_some_and(|entry| entry.surt_url == surt_url)
+        {
+            let entry = crawl.entries.pop_front().unwrap();
+            let is_preferred = preferred.as_ref().is_none_or(|(priority, current)| {
+                (crawl.priority, &entry.timestamp) > (*priority, &current.timestamp)
+            });
+            if preferred.is_some() {
+                SUPERSEDED_CAPTURES_COUNTER.inc();
+            }
+            if is_preferred {
+                preferred = Some((crawl.priority, entry));
+            }
+        }
+    }
+    preferred.map(|(_, entry)| entry)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pop_preferred_capture, CrawlIndex};
+    use crate::commoncrawl::parse_cdx_line;
+
+    fn crawl(priority: i64, lines: &[(&str, &str)]) -> CrawlIndex {
+        let mut crawl = CrawlIndex::new("CC-MAIN-test".to_string(), priority, Vec::new());
+        crawl.entries = lines
+            .iter()
+            .map(|(surt_url, timestamp)| {
+                parse_cdx_line(&format!(
+                    r#"{} {} {{"url": "https://example.com/", "status": "200", "length": "1", "offset": "0", "filename": "{}.warc.gz"}}"#,
+                    surt_url, timestamp, timestamp
+                ))
+            })
+            .collect();
+        crawl
+    }
+
+    #[test]
+    fn prefers_newest_capture_and_crawl_priority() {
+        let mut crawls = vec![
+            crawl(
+                0,
+                &[
+                    ("com,example)/", "20240722120756"),
+                    ("com,example)/a", "20240722120756"),
+                ],
+            ),
+            crawl(
+                0,
+                &[
+                    ("com,example)/", "20240101000000"),
+                    ("com,example)/", "20240801000000"),
+                ],
+            ),
+        ];
+        let entry = pop_preferred_capture(&mut crawls, "com,example)/").unwrap();
+        assert_eq!(entry.timestamp, "20240801000000");
+        assert!(crawls[1].entries.is_empty());
+        assert_eq!(crawls[0].entries.len(), 1);
+
+        crawls[1] = crawl(1, &[("com,example)/a", "20240101000000")]);
+        let entry = pop_preferred_capture(&mut crawls, "com,example)/a").unwrap();
+        assert_eq!(entry.timestamp, "20240101000000");
+        assert!(pop_preferred_capture(&mut crawls, "com,example)/b").is_none());
+    }
+}
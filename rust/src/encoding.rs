@@ -0,0 +1,248 @@
+//! This module contains helper functions to determine the character encoding of an HTML payload
+//! and to transcode it to UTF-8 before text extraction.
+//!
+//! The encoding is determined from the following sources, in this order of priority:
+//!
+//! 1. A byte order mark at the beginning of the payload
+//! 2. The `charset` parameter of the HTTP `Content-Type` header
+//! 3. The `<meta charset>` or `<meta http-equiv="Content-Type">` tag in the HTML
+//! 4. The `charset` field of the cdx index entry
+//! 5. A statistical detector (chardetng)
+use encoding_rs::{Encoding, UTF_16BE, UTF_16LE, UTF_8};
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+
+/// Number of bytes at the beginning of the payload that we scan for a meta charset.
+const META_PRESCAN_BYTES: usize = 4096;
+
+lazy_static! {
+    static ref DETECTED_ENCODINGS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "detected_encodings",
+        "Number of HTML payloads per detected encoding and source of the detection",
+        &["encoding", "source"]
+    )
+    .unwrap();
+    static ref DECODING_FAILURES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "decoding_failures",
+        "Number of HTML payloads that could not be decoded without errors",
+        &["encoding"]
+    )
+    .unwrap();
+}
+
+/// The source from which the encoding of a payload was determined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncodingSource {
+    ByteOrderMark,
+    ContentType,
+    MetaCharset,
+    CdxCharset,
+    Detector,
+}
+
+impl EncodingSource {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EncodingSource::ByteOrderMark => "bom",
+            EncodingSource::ContentType => "content_type",
+            EncodingSource::MetaCharset => "meta_charset",
+            EncodingSource::CdxCharset => "cdx_charset",
+            EncodingSource::Detector => "detector",
+        }
+    }
+}
+
+/// An HTML payload that has been transcoded to UTF-8.
+#[derive(Debug)]
+pub struct DecodedHtml {
+    pub html: String,
+    pub encoding: &'static Encoding,
+    pub source: EncodingSource,
+    /// Whether malformed sequences were replaced with U+FFFD even after the retry with the detected encoding.
+    pub had_errors: bool,
+}
+
+/// Determines the encoding of an HTML payload.
+/// `content_type` is the value of the HTTP `Content-Type` header and
+/// `cdx_charset` is the `charset` field of the cdx index entry, if present.
+pub fn detect_encoding(
+    payload: &[u8],
+    content_type: Option<&str>,
+    cdx_charset: Option<&str>,
+) -> (&'static Encoding, EncodingSource) {
+    if let Some((encoding, _)) = Encoding::for_bom(payload) {
+        return (encoding, EncodingSource::ByteOrderMark);
+    }
+    if let Some(encoding) = content_type
+        .and_then(charset_from_content_type)
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+    {
+        return (encoding, EncodingSource::ContentType);
+    }
+    if let Some(encoding) = meta_charset(payload) {
+        return (encoding, EncodingSource::MetaCharset);
+    }
+    if let Some(encoding) = cdx_charset.and_then(|label| Encoding::for_label(label.as_bytes())) {
+        return (encoding, EncodingSource::CdxCharset);
+    }
+    (detect_statistically(payload), EncodingSource::Detector)
+}
+
+/// Transcodes an HTML payload to UTF-8 using the encoding found by [detect_encoding].
+/// If the payload contains malformed sequences for that encoding, we retry with the encoding
+/// guessed by the statistical detector because declared charsets are frequently wrong.
+/// Payloads that still contain malformed sequences are decoded lossily and counted, a few replacement
+/// characters are not worth losing the whole document.
+pub fn decode_html(
+    payload: &[u8],
+    content_type: Option<&str>,
+    cdx_charset: Option<&str>,
+) -> DecodedHtml {
+    let (mut encoding, mut source) = detect_encoding(payload, content_type, cdx_charset);
+    let (mut html, mut had_errors) = decode(payload, encoding);
+    if had_errors && source != EncodingSource::Detector {
+        let detected = detect_statistically(payload);
+        if detected != encoding {
+            tracing::debug!(
+                "Failed to decode payload as {}, retrying with detected encoding {}",
+                encoding.name(),
+                detected.name()
+            );
+            (html, had_errors) = decode(payload, detected);
+            encoding = detected;
+            source = EncodingSource::Detector;
+        }
+    }
+    if had_errors {
+        DECODING_FAILURES_COUNTER
+            .with_label_values(&[encoding.name()])
+            .inc();
+        tracing::debug!(
+            "Payload contains malformed sequences for encoding {}",
+            encoding.name()
+        );
+    }
+    DETECTED_ENCODINGS_COUNTER
+        .with_label_values(&[encoding.name(), source.as_str()])
+        .inc();
+    DecodedHtml {
+        html,
+        encoding,
+        source,
+        had_errors,
+    }
+}
+
+/// Decodes the payload, removing a BOM if present, and reports whether malformed sequences were replaced.
+fn decode(payload: &[u8], encoding: &'static Encoding) -> (String, bool) {
+    let (html, _, had_errors) = encoding.decode(payload);
+    (html.into_owned(), had_errors)
+}
+
+fn detect_statistically(payload: &[u8]) -> &'static Encoding {
+    let mut detector = chardetng::EncodingDetector::new();
+    detector.feed(payload, true);
+    detector.guess(None, true)
+}
+
+/// Extracts the `charset` parameter from a `Content-Type` value such as `text/html; charset="utf-8"`.
+fn charset_from_content_type(content_type: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if key.trim().eq_ignore_ascii_case("charset") {
+            Some(value.trim().trim_matches(['"', '\'']).to_string())
+        } else {
+            None
+        }
+    })
+}
+
+/// Scans the beginning of the payload for a `<meta>` tag that declares a charset.
+/// This covers both `<meta charset="...">` and `<meta http-equiv="Content-Type" content="...; charset=...">`.
+fn meta_charset(payload: &[u8]) -> Option<&'static Encoding> {
+    let prefix = payload[..payload.len().min(META_PRESCAN_BYTES)].to_ascii_lowercase();
+    let mut rest = prefix.as_slice();
+    while let Some(start) = find(rest, b"<meta") {
+        let tag = &rest[start..];
+        let tag = &tag[..find(tag, b">").unwrap_or(tag.len())];
+        if let Some(encoding) = charset_attribute(tag).and_then(Encoding::for_label) {
+            // A page cannot declare itself as UTF-16 in ASCII-compatible bytes, see the HTML standard.
+            if encoding == UTF_16BE || encoding == UTF_16LE {
+                return Some(UTF_8);
+            }
+            return Some(encoding);
+        }
+        rest = &rest[start + 5..];
+    }
+    None
+}
+
+/// Finds the value following `charset=` within a lowercased meta tag.
+fn charset_attribute(tag: &[u8]) -> Option<&[u8]> {
+    let position = find(tag, b"charset")?;
+    let value = tag[position + 7..].trim_ascii_start().strip_prefix(b"=")?;
+    let value = value.trim_ascii_start();
+    let value = value
+        .strip_prefix(b"\"")
+        .or_else(|| value.strip_prefix(b"'"))
+        .unwrap_or(value);
+    let end = value
+        .iter()
+        .position(|b| matches!(b, b'"' | b'\'' | b';' | b'/' | b'>') || b.is_ascii_whitespace())
+        .unwrap_or(value.len());
+    Some(&value[..end])
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+#[cfg(test)]
+mod tests {
+    use encoding_rs::{SHIFT_JIS, UTF_8, WINDOWS_1252};
+
+    use super::{decode_html, detect_encoding, EncodingSource};
+
+    #[test]
+    fn content_type_takes_precedence_over_meta_and_cdx_charset() {
+        let html = br#"<html><head><meta charset="utf-8"></head></html>"#;
+        let (encoding, source) = detect_encoding(
+            html,
+            Some("text/html; charset=\"Shift_JIS\""),
+            Some("UTF-8"),
+        );
+        assert_eq!(encoding, SHIFT_JIS);
+        assert_eq!(source, EncodingSource::ContentType);
+    }
+
+    #[test]
+    fn can_detect_meta_http_equiv_charset() {
+        let html = br#"<html><head><META http-equiv="Content-Type" content="text/html; charset=windows-1252"></head></html>"#;
+        let (encoding, source) = detect_encoding(html, Some("text/html"), Some("UTF-8"));
+        assert_eq!(encoding, WINDOWS_1252);
+        assert_eq!(source, EncodingSource::MetaCharset);
+    }
+
+    #[test]
+    fn falls_back_to_detector_if_declared_charset_is_wrong() {
+        let html = "<html><body>Grüße aus Köln, schöne Straße</body></html>";
+        let (latin1, _, _) = WINDOWS_1252.encode(html);
+        let decoded = decode_html(&latin1, Some("text/html; charset=utf-8"), None);
+        assert_eq!(decoded.html, html);
+        assert_eq!(decoded.source, EncodingSource::Detector);
+
+        let decoded = decode_html(html.as_bytes(), None, Some("UTF-8"));
+        assert_eq!(decoded.encoding, UTF_8);
+        assert_eq!(decoded.source, EncodingSource::CdxCharset);
+        assert!(!decoded.had_errors);
+    }
+
+    #[test]
+    fn decodes_malformed_payloads_lossily() {
+        // A UTF-16 payload with a truncated last code unit.
+        let payload = b"\xFF\xFEH\x00i\x00!";
+        let decoded = decode_html(payload, None, None);
+        assert_eq!(decoded.html, "Hi\u{FFFD}");
+        assert!(decoded.had_errors);
+    }
+}
@@ -0,0 +1,155 @@
+//! This module contains the [Extractor] trait that abstracts over the different text extraction backends.
+//!
+//! Three backends are available:
+//!
+//! - [TrafilaturaExtractor] calls the Python package trafilatura via PyO3. It requires the `trafilatura` cargo feature
+//!   (enabled by default) and a Python installation with trafilatura on the `PYTHONPATH`.
+//! - [TrafilaturaPoolExtractor] runs trafilatura in a pool of Python subprocesses with per-document timeouts
+//!   and memory limits, see [crate::trafilatura_pool].
+//! - [ReadabilityExtractor] is a native Rust boilerplate-removal extractor, see [crate::readability].
+//!   It does not need Python, so workers built with `--no-default-features` can be deployed without it.
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    trafilatura::TrafilaturaConfig,
+    trafilatura_pool::{TrafilaturaPool, TrafilaturaPoolConfig},
+};
+
+/// The main text of an HTML document together with the metadata that the extractor found.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ExtractedDocument {
+    pub text: String,
+    pub title: Option<String>,
+    pub author: Option<String>,
+    /// The publication date as found in the document, usually in ISO 8601 format.
+    pub date: Option<String>,
+    pub sitename: Option<String>,
+    pub description: Option<String>,
+    pub categories: Vec<String>,
+    pub tags: Vec<String>,
+    pub canonical_url: Option<String>,
+}
+
+/// Extracts the main text and metadata from an HTML document.
+pub trait Extractor: Send + Sync {
+    /// Extract text and metadata from `html` and return them if successful.
+    /// `url` is the URL of the document, if known.
+    /// Might return `Ok(None)` if text extraction was not successful.
+    fn extract(
+        &self,
+        html: &str,
+        url: Option<&str>,
+    ) -> Result<Option<ExtractedDocument>, anyhow::Error>;
+}
+
+/// Extracts text using the Python package trafilatura with the given options.
+#[cfg(feature = "trafilatura")]
+pub struct TrafilaturaExtractor {
+    config: TrafilaturaConfig,
+}
+
+#[cfg(feature = "trafilatura")]
+impl TrafilaturaExtractor {
+    pub fn new(config: TrafilaturaConfig) -> Self {
+        Self { config }
+    }
+}
+
+#[cfg(feature = "trafilatura")]
+impl Extractor for TrafilaturaExtractor {
+    fn extract(
+        &self,
+        html: &str,
+        url: Option<&str>,
+    ) -> Result<Option<ExtractedDocument>, anyhow::Error> {
+        crate::trafilatura::extract_with_metadata(html, &self.config.for_document(url))
+    }
+}
+
+/// Extracts text using the Python package trafilatura in a pool of subprocesses.
+pub struct TrafilaturaPoolExtractor {
+    pool: TrafilaturaPool,
+    config: TrafilaturaConfig,
+}
+
+impl TrafilaturaPoolExtractor {
+    pub fn new(
+        config: TrafilaturaConfig,
+        pool_config: TrafilaturaPoolConfig,
+    ) -> Result<Self, anyhow::Error> {
+        Ok(Self {
+            pool: TrafilaturaPool::new(pool_config)?,
+            config,
+        })
+    }
+}
+
+impl Extractor for TrafilaturaPoolExtractor {
+    fn extract(
+        &self,
+        html: &str,
+        url: Option<&str>,
+    ) -> Result<Option<ExtractedDocument>, anyhow::Error> {
+        self.pool
+            .extract_with_metadata(html, &self.config.for_document(url))
+    }
+}
+
+/// Extracts text using the native Rust implementation in [crate::readability].
+pub struct ReadabilityExtractor;
+
+impl Extractor for ReadabilityExtractor {
+    fn extract(
+        &self,
+        html: &str,
+        _url: Option<&str>,
+    ) -> Result<Option<ExtractedDocument>, anyhow::Error> {
+        Ok(crate::readability::extract_with_metadata(html))
+    }
+}
+
+/// The available extraction backends, selectable at runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExtractorKind {
+    #[cfg(feature = "trafilatura")]
+    Trafilatura,
+    TrafilaturaSubprocess,
+    Readability,
+}
+
+impl Default for ExtractorKind {
+    #[cfg(feature = "trafilatura")]
+    fn default() -> Self {
+        ExtractorKind::Trafilatura
+    }
+
+    #[cfg(not(feature = "trafilatura"))]
+    fn default() -> Self {
+        ExtractorKind::Readability
+    }
+}
+
+/// Creates the extractor for the given backend.
+/// The `trafilatura_config` is only used by the trafilatura backends and
+/// the `pool_config` only by the subprocess backend, which can fail to start its subprocesses.
+pub fn new_extractor(
+    kind: ExtractorKind,
+    trafilatura_config: &TrafilaturaConfig,
+    pool_config: &TrafilaturaPoolConfig,
+) -> Result<Arc<dyn Extractor>, anyhow::Error> {
+    Ok(match kind {
+        #[cfg(feature = "trafilatura")]
+        ExtractorKind::Trafilatura => {
+            Arc::new(TrafilaturaExtractor::new(trafilatura_config.clone()))
+        }
+        ExtractorKind::TrafilaturaSubprocess => Arc::new(TrafilaturaPoolExtractor::new(
+            trafilatura_config.clone(),
+            pool_config.clone(),
+        )?),
+        ExtractorKind::Readability => Arc::new(ReadabilityExtractor),
+    })
+}
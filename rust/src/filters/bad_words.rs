@@ -0,0 +1,233 @@
+//! A filter for documents with offensive language, based on per-language lexicons of bad words.
+//!
+//! The lexicons are plain text files with one word or phrase per line, such as the
+//! "List of Dirty, Naughty, Obscene, and Otherwise Bad Words" that was used for the C4 dataset.
+//! All words of all lexicons are compiled into a single Aho-Corasick automaton, so that a document is scanned once,
+//! independent of the size of the lexicons. A document fails the filter if the fraction of its words that are
+//! bad words exceeds a threshold, or if its URL contains a bad word.
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::PathBuf,
+};
+
+use aho_corasick::{AhoCorasick, MatchKind};
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use super::{ratio, words, FilterFailure};
+
+/// Configuration of the bad words filter.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BadWordsConfig {
+    pub enabled: bool,
+    /// Paths to the lexicon files per language, e.g. `eng = "bad_words/en.txt"`.
+    /// Documents whose language is unknown or has no lexicon are checked against all lexicons.
+    pub lexicons: BTreeMap<String, PathBuf>,
+    /// Maximum fraction of the words of a document that are bad words. Phrases count as one word.
+    pub max_bad_word_fraction: f64,
+    /// Whether words only match at word boundaries, so that e.g. `ass` does not match `class`.
+    /// Disable it for languages that are written without spaces.
+    pub whole_words: bool,
+    /// Whether documents whose URL contains a bad word fail the filter.
+    pub check_url: bool,
+}
+
+impl Default for BadWordsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lexicons: BTreeMap::new(),
+            max_bad_word_fraction: 0.01,
+            whole_words: true,
+            check_url: true,
+        }
+    }
+}
+
+/// The rules of the bad words filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BadWordsRule {
+    BadWordFraction,
+    UrlKeyword,
+}
+
+impl BadWordsRule {
+    /// The name of the rule, used as a metrics label and in output annotations.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BadWordsRule::BadWordFraction => "bad_word_fraction",
+            BadWordsRule::UrlKeyword => "url_keyword",
+        }
+    }
+}
+
+/// The compiled lexicons of a [BadWordsConfig].
+pub struct BadWordsFilter {
+    matcher: AhoCorasick,
+    /// The languages that have a lexicon.
+    languages: BTreeSet<String>,
+    /// The languages of every pattern of the matcher.
+    pattern_languages: Vec<BTreeSet<String>>,
+    max_bad_word_fraction: f64,
+    whole_words: bool,
+    check_url: bool,
+}
+
+impl BadWordsFilter {
+    /// Loads the lexicons of the config. Returns `Ok(None)` if the filter is disabled.
+    pub fn from_config(config: &BadWordsConfig) -> Result<Option<Self>, anyhow::Error> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let mut lexicons = BTreeMap::new();
+        for (language, path) in &config.lexicons {
+            let content = std::fs::read_to_string(path)
+                .with_context(|| format!("Failed to read lexicon {}", path.display()))?;
+            lexicons.insert(language.clone(), content);
+        }
+        let filter = Self::new(
+            lexicons.iter().map(|(l, c)| (l.as_str(), c.as_str())),
+            config,
+        )?;
+        tracing::info!(
+            "Loaded {} bad words for languages {:?}",
+            filter.pattern_languages.len(),
+            config.lexicons.keys().collect::<Vec<_>>()
+        );
+        Ok(Some(filter))
+    }
+
+    /// Compiles lexicons, given as pairs of language and file content.
+    pub fn new<'a>(
+        lexicons: impl IntoIterator<Item = (&'a str, &'a str)>,
+        config: &BadWordsConfig,
+    ) -> Result<Self, anyhow::Error> {
+        let mut words: BTreeMap<String, BTreeSet<String>> = BTreeMap::new();
+        let mut languages = BTreeSet::new();
+        for (language, lexicon) in lexicons {
+            for line in lexicon.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                words
+                    .entry(normalize(line))
+                    .or_default()
+                    .insert(language.to_string());
+            }
+            languages.insert(language.to_string());
+        }
+        let matcher = AhoCorasick::builder()
+            .match_kind(MatchKind::LeftmostLongest)
+            .build(words.keys())?;
+        Ok(Self {
+            matcher,
+            languages,
+            pattern_languages: words.into_values().collect(),
+            max_bad_word_fraction: config.max_bad_word_fraction,
+            whole_words: config.whole_words,
+            check_url: config.check_url,
+        })
+    }
+
+    /// Returns the number of bad words in the normalized text, using only the lexicon of `language` if there is one.
+    fn count_matches(&self, normalized: &str, language: Option<&str>) -> usize {
+        let language = language.filter(|language| self.languages.contains(*language));
+        self.matcher
+            .find_iter(normalized)
+            .filter(|m| {
+                language.is_none_or(|l| self.pattern_languages[m.pattern().as_usize()].contains(l))
+            })
+            .filter(|m| !self.whole_words || is_whole_word(normalized, m.start(), m.end()))
+            .count()
+    }
+
+    /// Returns all rules that the document fails. `language` is the identified language of the text, if any.
+    pub fn check(&self, url: &str, text: &str, language: Option<&str>) -> Vec<FilterFailure> {
+        let mut failures = Vec::new();
+        let num_words = words(text).count();
+        let bad_words = self.count_matches(&normalize(text), language);
+        if ratio(bad_words, num_words) > self.max_bad_word_fraction {
+            failures.push(BadWordsRule::BadWordFraction);
+        }
+        // Separators in URLs such as `-` and `/` become spaces, so that words and phrases match at their boundaries.
+        if self.check_url && self.count_matches(&normalize(url), language) > 0 {
+            failures.push(BadWordsRule::UrlKeyword);
+        }
+        failures
+            .into_iter()
+            .map(|rule| FilterFailure {
+                filter: "bad_words",
+                rule: rule.as_str(),
+            })
+            .collect()
+    }
+}
+
+/// Lowercases the text and replaces every sequence of characters that are not letters, digits or apostrophes
+/// with a single space.
+fn normalize(text: &str) -> String {
+    let mut normalized = String::with_capacity(text.len());
+    for word in text
+        .split(|c: char| !c.is_alphanumeric() && c != '\'')
+        .filter(|word| !word.is_empty())
+    {
+        if !normalized.is_empty() {
+            normalized.push(' ');
+        }
+        normalized.extend(word.chars().flat_map(char::to_lowercase));
+    }
+    normalized
+}
+
+fn is_whole_word(text: &str, start: usize, end: usize) -> bool {
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '\'';
+    text[..start]
+        .chars()
+        .next_back()
+        .is_none_or(|c| !is_word_char(c))
+        && text[end..].chars().next().is_none_or(|c| !is_word_char(c))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BadWordsConfig, BadWordsFilter};
+
+    #[test]
+    fn flags_bad_words() {
+        let config = BadWordsConfig {
+            max_bad_word_fraction: 0.1,
+            ..Default::default()
+        };
+        let filter = BadWordsFilter::new(
+            [("eng", "# English\ndamn\nbloody hell\n"), ("deu", "mist\n")],
+            &config,
+        )
+        .unwrap();
+        let rules = |url, text, language| {
+            filter
+                .check(url, text, language)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        };
+        let clean = "The damnation of Faust is an opera by Hector Berlioz.";
+        assert!(rules("https://example.com/opera", clean, Some("eng")).is_empty());
+
+        let text = "Damn! It is bloody   hell outside, damn this weather.";
+        assert_eq!(
+            rules("https://example.com/weather", text, Some("eng")),
+            ["bad_words.bad_word_fraction"]
+        );
+        // Only the German lexicon is used for German documents, but all lexicons for unknown languages.
+        assert!(rules("https://example.com/", text, Some("deu")).is_empty());
+        assert_eq!(
+            rules(
+                "https://example.com/so-ein-mist",
+                "Das Wetter ist schön.",
+                None
+            ),
+            ["bad_words.url_keyword"]
+        );
+    }
+}
@@ -0,0 +1,290 @@
+//! The quality heuristics from the Gopher/MassiveText paper (Rae et al., 2021, Appendix A.1.1).
+//!
+//! A document is kept if
+//!
+//! - it has between 50 and 100,000 words,
+//! - its mean word length is between 3 and 10 characters,
+//! - its ratio of symbols (`#`, `...` and `…`) to words is at most 0.1,
+//! - at most 90% of its lines start with a bullet point,
+//! - at most 30% of its lines end with an ellipsis,
+//! - at least 80% of its words contain an alphabetic character, and
+//! - it contains at least two stop words such as `the`, `and` or `with`.
+//!
+//! All thresholds can be changed in the [GopherQualityConfig]. The stop words are configured per language, and only
+//! English ones are configured by default. The stop word rule is skipped for documents in languages without stop words,
+//! and the rules on the number and length of words are skipped for languages that are written without spaces.
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{ratio, words};
+
+/// The stop words from the paper. A natural English text contains several of them.
+const DEFAULT_STOP_WORDS: &[&str] = &["the", "be", "to", "of", "and", "that", "have", "with"];
+
+/// Languages that are written without spaces between words, as ISO 639-3 codes.
+const DEFAULT_UNSEGMENTED_LANGUAGES: &[&str] = &["cmn", "jpn", "tha", "khm", "mya"];
+
+/// Characters that mark a line as an item of a bulleted list.
+const BULLETS: &[char] = &[
+    '•', '●', '○', '◦', '▪', '▫', '■', '□', '‣', '⁃', '-', '*', '–',
+];
+
+const ELLIPSES: &[&str] = &["...", "…"];
+
+/// Thresholds of the Gopher quality filter.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GopherQualityConfig {
+    pub enabled: bool,
+    pub min_words: usize,
+    pub max_words: usize,
+    pub min_mean_word_length: f64,
+    pub max_mean_word_length: f64,
+    /// Maximum ratio of `#` and ellipses to words.
+    pub max_symbol_word_ratio: f64,
+    /// Maximum fraction of non-empty lines that start with a bullet point.
+    pub max_bullet_lines_ratio: f64,
+    /// Maximum fraction of non-empty lines that end with an ellipsis.
+    pub max_ellipsis_lines_ratio: f64,
+    /// Minimum fraction of words that contain at least one alphabetic character.
+    pub min_alpha_words_ratio: f64,
+    /// Minimum number of occurrences of the stop words of the language of a document.
+    pub min_stop_words: usize,
+    /// Lower-case stop words per language, e.g. `eng = ["the", "and"]`. Documents in other languages are not
+    /// checked for stop words, and documents whose language is unknown are checked against all stop words.
+    pub stop_words: BTreeMap<String, Vec<String>>,
+    /// Languages that are written without spaces between words, for which the rules on the number of words
+    /// and the mean word length are skipped.
+    pub unsegmented_languages: Vec<String>,
+}
+
+impl Default for GopherQualityConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            min_words: 50,
+            max_words: 100_000,
+            min_mean_word_length: 3.0,
+            max_mean_word_length: 10.0,
+            max_symbol_word_ratio: 0.1,
+            max_bullet_lines_ratio: 0.9,
+            max_ellipsis_lines_ratio: 0.3,
+            min_alpha_words_ratio: 0.8,
+            min_stop_words: 2,
+            stop_words: BTreeMap::from([(
+                "eng".to_string(),
+                DEFAULT_STOP_WORDS.iter().map(|w| w.to_string()).collect(),
+            )]),
+            unsegmented_languages: DEFAULT_UNSEGMENTED_LANGUAGES
+                .iter()
+                .map(|l| l.to_string())
+                .collect(),
+        }
+    }
+}
+
+/// The rules of the Gopher quality filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GopherRule {
+    MinWords,
+    MaxWords,
+    MeanWordLength,
+    SymbolWordRatio,
+    BulletLines,
+    EllipsisLines,
+    AlphaWords,
+    StopWords,
+}
+
+impl GopherRule {
+    /// The name of the rule, used as a metrics label and in output annotations.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            GopherRule::MinWords => "min_words",
+            GopherRule::MaxWords => "max_words",
+            GopherRule::MeanWordLength => "mean_word_length",
+            GopherRule::SymbolWordRatio => "symbol_word_ratio",
+            GopherRule::BulletLines => "bullet_lines",
+            GopherRule::EllipsisLines => "ellipsis_lines",
+            GopherRule::AlphaWords => "alpha_words",
+            GopherRule::StopWords => "stop_words",
+        }
+    }
+}
+
+impl GopherQualityConfig {
+    /// Returns all rules that `text` in `language`, if it is known, fails, in the order in which they are listed
+    /// in [GopherRule].
+    pub fn failures(&self, text: &str, language: Option<&str>) -> Vec<GopherRule> {
+        let words: Vec<&str> = words(text).collect();
+        let segmented = language
+            .is_none_or(|language| !self.unsegmented_languages.iter().any(|l| l == language));
+        let mut failures = Vec::new();
+        if segmented && words.len() < self.min_words {
+            failures.push(GopherRule::MinWords);
+        }
+        if segmented && words.len() > self.max_words {
+            failures.push(GopherRule::MaxWords);
+        }
+        if words.is_empty() {
+            return failures;
+        }
+
+        // Punctuation such as a dash between spaces is not a word for the mean word length.
+        let lengths: Vec<usize> = words
+            .iter()
+            .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric()))
+            .filter(|word| !word.is_empty())
+            .map(|word| word.chars().count())
+            .collect();
+        let mean_word_length = ratio(lengths.iter().sum(), lengths.len());
+        if segmented
+            && (mean_word_length < self.min_mean_word_length
+                || mean_word_length > self.max_mean_word_length)
+        {
+            failures.push(GopherRule::MeanWordLength);
+        }
+
+        let symbols = text.matches('#').count()
+            + ELLIPSES
+                .iter()
+                .map(|e| text.matches(e).count())
+                .sum::<usize>();
+        if ratio(symbols, words.len()) > self.max_symbol_word_ratio {
+            failures.push(GopherRule::SymbolWordRatio);
+        }
+
+        let lines: Vec<&str> = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect();
+        let bullet_lines = lines
+            .iter()
+            .filter(|line| line.starts_with(BULLETS))
+            .count();
+        if ratio(bullet_lines, lines.len()) > self.max_bullet_lines_ratio {
+            failures.push(GopherRule::BulletLines);
+        }
+        let ellipsis_lines = lines
+            .iter()
+            .filter(|line| ELLIPSES.iter().any(|e| line.ends_with(e)))
+            .count();
+        if ratio(ellipsis_lines, lines.len()) > self.max_ellipsis_lines_ratio {
+            failures.push(GopherRule::EllipsisLines);
+        }
+
+        let alpha_words = words
+            .iter()
+            .filter(|word| word.chars().any(char::is_alphabetic))
+            .count();
+        if ratio(alpha_words, words.len()) < self.min_alpha_words_ratio {
+            failures.push(GopherRule::AlphaWords);
+        }
+
+        let stop_words: Vec<&String> = match language {
+            Some(language) => self
+                .stop_words
+                .get(language)
+                .into_iter()
+                .flatten()
+                .collect(),
+            None => self.stop_words.values().flatten().collect(),
+        };
+        if stop_words.is_empty() {
+            return failures;
+        }
+        let stop_word_count = words
+            .iter()
+            .map(|word| {
+                word.trim_matches(|c: char| !c.is_alphanumeric())
+                    .to_lowercase()
+            })
+            .filter(|word| stop_words.contains(&word))
+            .count();
+        if stop_word_count < self.min_stop_words {
+            failures.push(GopherRule::StopWords);
+        }
+        failures
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GopherQualityConfig, GopherRule};
+
+    const ARTICLE: &str = "The history of the city goes back to the Roman period, when a small settlement was founded at the crossing of two important trade routes.
+Over the following centuries, the settlement grew into a market town with a church, a mill and a bridge over the river.
+Today, the city is known for its old town and for the university that was founded in the fifteenth century.";
+
+    #[test]
+    fn keeps_natural_text() {
+        assert_eq!(
+            GopherQualityConfig::default().failures(ARTICLE, Some("eng")),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn reports_failing_rules() {
+        let config = GopherQualityConfig::default();
+        assert_eq!(
+            config.failures("Home About Contact", None),
+            vec![GopherRule::MinWords, GopherRule::StopWords]
+        );
+
+        let menu = (0..60)
+            .map(|i| format!("• Item number {} ...", i))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(
+            config.failures(&menu, Some("eng")),
+            vec![
+                GopherRule::SymbolWordRatio,
+                GopherRule::BulletLines,
+                GopherRule::EllipsisLines,
+                GopherRule::AlphaWords,
+                GopherRule::StopWords
+            ]
+        );
+
+        let numbers = (0..60).map(|i| i.to_string()).collect::<Vec<_>>().join(" ");
+        assert_eq!(
+            config.failures(&numbers, None),
+            vec![
+                GopherRule::MeanWordLength,
+                GopherRule::AlphaWords,
+                GopherRule::StopWords
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_rules_that_do_not_apply_to_the_language() {
+        let config = GopherQualityConfig::default();
+        let german = "Die Geschichte der Stadt reicht bis in die Römerzeit zurück, als an der Kreuzung \
+            zweier wichtiger Handelswege eine kleine Siedlung gegründet wurde. In den folgenden Jahrhunderten \
+            wuchs die Siedlung zu einer Marktstadt mit einer Kirche, einer Mühle und einer Brücke über den Fluss.";
+        assert_eq!(
+            config.failures(german, None),
+            vec![GopherRule::MinWords, GopherRule::StopWords]
+        );
+        assert_eq!(
+            config.failures(german, Some("deu")),
+            vec![GopherRule::MinWords]
+        );
+
+        let chinese =
+            "这座城市的历史可以追溯到罗马时期。当时在两条重要商路的交汇处建立了一个小定居点。";
+        assert_eq!(config.failures(chinese, Some("cmn")), vec![]);
+        assert_eq!(
+            config.failures(chinese, Some("eng")),
+            vec![
+                GopherRule::MinWords,
+                GopherRule::MeanWordLength,
+                GopherRule::StopWords
+            ]
+        );
+    }
+}
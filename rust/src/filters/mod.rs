@@ -0,0 +1,161 @@
+//! This module contains the quality filters that the worker applies to the extracted text.
+//!
+//! Every filter consists of several rules with configurable thresholds. A document fails a filter if it
+//! breaks at least one of its rules. Depending on the [FilterMode], failing documents are either dropped
+//! or kept and annotated with the names of the rules that they broke, which makes it possible to tune
+//! the thresholds on real data before dropping anything.
+use std::fmt;
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+
+pub mod bad_words;
+pub mod gopher;
+pub mod repetition;
+
+use bad_words::BadWordsConfig;
+use gopher::GopherQualityConfig;
+use repetition::RepetitionConfig;
+
+lazy_static! {
+    static ref FILTER_FAILURES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "quality_filter_failures",
+        "Number of quality filter rules that documents failed, per filter, rule and whether the document was dropped or annotated",
+        &["filter", "rule", "action"]
+    )
+    .unwrap();
+}
+
+/// What happens to documents that fail a quality filter.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FilterMode {
+    /// Failing documents are not written to the output.
+    #[default]
+    Drop,
+    /// Failing documents are written to the output together with the rules that they failed.
+    Annotate,
+}
+
+impl FilterMode {
+    /// What happened to a failing document, used as a metrics label.
+    fn action(&self) -> &'static str {
+        match self {
+            FilterMode::Drop => "dropped",
+            FilterMode::Annotate => "annotated",
+        }
+    }
+}
+
+/// A rule of a quality filter that a document failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FilterFailure {
+    pub filter: &'static str,
+    pub rule: &'static str,
+}
+
+impl fmt::Display for FilterFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.filter, self.rule)
+    }
+}
+
+/// Configuration of all quality filters.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QualityFilterConfig {
+    pub mode: FilterMode,
+    /// The heuristics from the Gopher/MassiveText paper.
+    pub gopher: GopherQualityConfig,
+    /// The repetition heuristics from the Gopher/MassiveText paper.
+    pub repetition: RepetitionConfig,
+    /// Lexicons of offensive words. Unlike the other filters, this filter needs to load files and is
+    /// therefore compiled into a [bad_words::BadWordsFilter] and not applied by [QualityFilterConfig::check].
+    pub bad_words: BadWordsConfig,
+}
+
+impl QualityFilterConfig {
+    /// Runs all enabled filters on `text` in `language`, if it is known, and returns all rules that it failed,
+    /// see [count_failures].
+    pub fn check(&self, text: &str, language: Option<&str>) -> Vec<FilterFailure> {
+        let mut failures = Vec::new();
+        if self.gopher.enabled {
+            failures.extend(
+                self.gopher
+                    .failures(text, language)
+                    .into_iter()
+                    .map(|rule| FilterFailure {
+                        filter: "gopher",
+                        rule: rule.as_str(),
+                    }),
+            );
+        }
+        if self.repetition.enabled {
+            failures.extend(
+                self.repetition
+                    .failures(text)
+                    .into_iter()
+                    .map(|rule| FilterFailure {
+                        filter: "repetition",
+                        rule: rule.as_str(),
+                    }),
+            );
+        }
+        failures
+    }
+}
+
+/// Counts every rule that a document failed in the `quality_filter_failures` metric,
+/// labeled with whether the document was dropped or annotated according to `mode`.
+pub fn count_failures(failures: &[FilterFailure], mode: FilterMode) {
+    for failure in failures {
+        FILTER_FAILURES_COUNTER
+            .with_label_values(&[failure.filter, failure.rule, mode.action()])
+            .inc();
+    }
+}
+
+/// Splits text into words at whitespace.
+pub(crate) fn words(text: &str) -> impl Iterator<Item = &str> {
+    text.split_whitespace()
+}
+
+/// Returns `part / total`, or 0 if `total` is 0.
+pub(crate) fn ratio(part: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        part as f64 / total as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count_failures, FilterFailure, FilterMode, FILTER_FAILURES_COUNTER};
+
+    #[test]
+    fn counts_every_failed_rule_per_action() {
+        let failures = [
+            FilterFailure {
+                filter: "test",
+                rule: "first",
+            },
+            FilterFailure {
+                filter: "test",
+                rule: "second",
+            },
+        ];
+        count_failures(&failures, FilterMode::Drop);
+        count_failures(&failures[1..], FilterMode::Annotate);
+        let count = |rule, action| {
+            FILTER_FAILURES_COUNTER
+                .with_label_values(&["test", rule, action])
+                .get()
+        };
+        assert_eq!(count("first", "dropped"), 1);
+        assert_eq!(count("second", "dropped"), 1);
+        assert_eq!(count("first", "annotated"), 0);
+        assert_eq!(count("second", "annotated"), 1);
+    }
+}
@@ -0,0 +1,291 @@
+//! The repetition heuristics from the Gopher/MassiveText paper (Rae et al., 2021, Appendix A.1.1, Table A1).
+//!
+//! Spam and boilerplate often repeat the same lines, paragraphs or phrases. A document fails this filter if
+//!
+//! - too many of its lines or paragraphs are duplicates, by count or by characters,
+//! - its most frequent n-gram covers too many characters, for small n, or
+//! - its duplicate n-grams cover too many characters, for larger n.
+//!
+//! Paragraphs are separated by empty lines. The extractors and the C4 cleaning rules emit one paragraph per line
+//! without empty lines in between, so text without empty lines has one paragraph per line, see [paragraphs].
+//! All character fractions are relative to the length of the whole text.
+use std::collections::{HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+
+use super::{ratio, words};
+
+const TOP_NGRAM_RULES: [&str; 9] = [
+    "top_2_gram_char_fraction",
+    "top_3_gram_char_fraction",
+    "top_4_gram_char_fraction",
+    "top_5_gram_char_fraction",
+    "top_6_gram_char_fraction",
+    "top_7_gram_char_fraction",
+    "top_8_gram_char_fraction",
+    "top_9_gram_char_fraction",
+    "top_10_gram_char_fraction",
+];
+
+const DUPLICATE_NGRAM_RULES: [&str; 9] = [
+    "duplicate_2_gram_char_fraction",
+    "duplicate_3_gram_char_fraction",
+    "duplicate_4_gram_char_fraction",
+    "duplicate_5_gram_char_fraction",
+    "duplicate_6_gram_char_fraction",
+    "duplicate_7_gram_char_fraction",
+    "duplicate_8_gram_char_fraction",
+    "duplicate_9_gram_char_fraction",
+    "duplicate_10_gram_char_fraction",
+];
+
+/// The maximum fraction of characters that n-grams of length `n` may cover.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NGramThreshold {
+    /// The number of words per n-gram, between 2 and 10.
+    pub n: usize,
+    pub max_fraction: f64,
+}
+
+/// Thresholds of the repetition filter. The defaults are the values from the paper.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RepetitionConfig {
+    pub enabled: bool,
+    /// Maximum fraction of lines that are duplicates of an earlier line.
+    pub max_duplicate_line_fraction: f64,
+    /// Maximum fraction of paragraphs that are duplicates of an earlier paragraph.
+    pub max_duplicate_paragraph_fraction: f64,
+    /// Maximum fraction of characters in lines that are duplicates of an earlier line.
+    pub max_duplicate_line_char_fraction: f64,
+    /// Maximum fraction of characters in paragraphs that are duplicates of an earlier paragraph.
+    pub max_duplicate_paragraph_char_fraction: f64,
+    /// Maximum fraction of characters covered by all occurrences of the most frequent n-gram.
+    pub top_ngrams: Vec<NGramThreshold>,
+    /// Maximum fraction of characters in n-grams that are duplicates of an earlier, non-overlapping n-gram.
+    pub duplicate_ngrams: Vec<NGramThreshold>,
+}
+
+impl Default for RepetitionConfig {
+    fn default() -> Self {
+        let thresholds = |values: &[(usize, f64)]| {
+            values
+                .iter()
+                .map(|&(n, max_fraction)| NGramThreshold { n, max_fraction })
+                .collect()
+        };
+        Self {
+            enabled: true,
+            max_duplicate_line_fraction: 0.3,
+            max_duplicate_paragraph_fraction: 0.3,
+            max_duplicate_line_char_fraction: 0.2,
+            max_duplicate_paragraph_char_fraction: 0.2,
+            top_ngrams: thresholds(&[(2, 0.2), (3, 0.18), (4, 0.16)]),
+            duplicate_ngrams: thresholds(&[
+                (5, 0.15),
+                (6, 0.14),
+                (7, 0.13),
+                (8, 0.12),
+                (9, 0.11),
+                (10, 0.1),
+            ]),
+        }
+    }
+}
+
+/// The rules of the repetition filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepetitionRule {
+    DuplicateLineFraction,
+    DuplicateParagraphFraction,
+    DuplicateLineCharFraction,
+    DuplicateParagraphCharFraction,
+    TopNGramCharFraction(usize),
+    DuplicateNGramCharFraction(usize),
+}
+
+impl RepetitionRule {
+    /// The name of the rule, used as a metrics label and in output annotations.
+    /// N-gram rules with an `n` outside of 2 to 10 share a generic name.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RepetitionRule::DuplicateLineFraction => "duplicate_line_fraction",
+            RepetitionRule::DuplicateParagraphFraction => "duplicate_paragraph_fraction",
+            RepetitionRule::DuplicateLineCharFraction => "duplicate_line_char_fraction",
+            RepetitionRule::DuplicateParagraphCharFraction => "duplicate_paragraph_char_fraction",
+            RepetitionRule::TopNGramCharFraction(n) => n
+                .checked_sub(2)
+                .and_then(|i| TOP_NGRAM_RULES.get(i))
+                .copied()
+                .unwrap_or("top_ngram_char_fraction"),
+            RepetitionRule::DuplicateNGramCharFraction(n) => n
+                .checked_sub(2)
+                .and_then(|i| DUPLICATE_NGRAM_RULES.get(i))
+                .copied()
+                .unwrap_or("duplicate_ngram_char_fraction"),
+        }
+    }
+}
+
+impl RepetitionConfig {
+    /// Returns all rules that `text` fails.
+    pub fn failures(&self, text: &str) -> Vec<RepetitionRule> {
+        let text_chars = text.chars().count();
+        let mut failures = Vec::new();
+
+        let paragraphs = paragraphs(text);
+        let (duplicates, duplicate_chars) = find_duplicates(&paragraphs);
+        if ratio(duplicates, paragraphs.len()) > self.max_duplicate_paragraph_fraction {
+            failures.push(RepetitionRule::DuplicateParagraphFraction);
+        }
+        if ratio(duplicate_chars, text_chars) > self.max_duplicate_paragraph_char_fraction {
+            failures.push(RepetitionRule::DuplicateParagraphCharFraction);
+        }
+
+        let lines: Vec<&str> = text
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .collect();
+        let (duplicates, duplicate_chars) = find_duplicates(&lines);
+        if ratio(duplicates, lines.len()) > self.max_duplicate_line_fraction {
+            failures.push(RepetitionRule::DuplicateLineFraction);
+        }
+        if ratio(duplicate_chars, text_chars) > self.max_duplicate_line_char_fraction {
+            failures.push(RepetitionRule::DuplicateLineCharFraction);
+        }
+
+        let words: Vec<&str> = words(text).collect();
+        for threshold in &self.top_ngrams {
+            if ratio(top_ngram_chars(&words, threshold.n), text_chars) > threshold.max_fraction {
+                failures.push(RepetitionRule::TopNGramCharFraction(threshold.n));
+            }
+        }
+        for threshold in &self.duplicate_ngrams {
+            if ratio(duplicate_ngram_chars(&words, threshold.n), text_chars)
+                > threshold.max_fraction
+            {
+                failures.push(RepetitionRule::DuplicateNGramCharFraction(threshold.n));
+            }
+        }
+        failures
+    }
+}
+
+/// Splits the text into paragraphs at empty lines or, if the text has no empty lines, into lines.
+fn paragraphs(text: &str) -> Vec<&str> {
+    let separator = if text.contains("\n\n") { "\n\n" } else { "\n" };
+    text.split(separator)
+        .map(str::trim)
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect()
+}
+
+/// Returns the number of elements that are duplicates of an earlier element and their number of characters.
+fn find_duplicates(elements: &[&str]) -> (usize, usize) {
+    let mut seen = HashSet::new();
+    let mut duplicates = 0;
+    let mut duplicate_chars = 0;
+    for element in elements {
+        if !seen.insert(*element) {
+            duplicates += 1;
+            duplicate_chars += element.chars().count();
+        }
+    }
+    (duplicates, duplicate_chars)
+}
+
+/// Returns the number of characters of all occurrences of the most frequent n-gram, if it occurs more than once.
+/// The characters of an n-gram are the characters of its words without the whitespace between them.
+fn top_ngram_chars(words: &[&str], n: usize) -> usize {
+    if n == 0 {
+        return 0;
+    }
+    let mut counts: HashMap<&[&str], usize> = HashMap::new();
+    for ngram in words.windows(n) {
+        *counts.entry(ngram).or_default() += 1;
+    }
+    counts
+        .into_iter()
+        .filter(|(_, count)| *count > 1)
+        .map(|(ngram, count)| ngram_chars(ngram) * count)
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns the number of characters in n-grams that repeat an earlier n-gram.
+/// After a duplicate, the scan continues behind it, so overlapping duplicates are not counted twice.
+fn duplicate_ngram_chars(words: &[&str], n: usize) -> usize {
+    if n == 0 {
+        return 0;
+    }
+    let mut seen = HashSet::new();
+    let mut duplicate_chars = 0;
+    let mut i = 0;
+    while i + n <= words.len() {
+        let ngram = &words[i..i + n];
+        if seen.insert(ngram) {
+            i += 1;
+        } else {
+            duplicate_chars += ngram_chars(ngram);
+            i += n;
+        }
+    }
+    duplicate_chars
+}
+
+fn ngram_chars(ngram: &[&str]) -> usize {
+    ngram.iter().map(|word| word.chars().count()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RepetitionConfig, RepetitionRule};
+    use crate::cleaning::{c4::C4CleaningConfig, TextCleaner};
+
+    #[test]
+    fn keeps_text_without_repetitions() {
+        let text = "The history of the city goes back to the Roman period.\n\nA small settlement was founded at the crossing of two trade routes.\n\nToday, the city is known for its old town and its university.";
+        assert_eq!(RepetitionConfig::default().failures(text), vec![]);
+    }
+
+    #[test]
+    fn reports_repeated_lines_and_ngrams() {
+        let text = [
+            "Buy cheap watches online now.",
+            "Best prices for cheap watches.",
+        ]
+        .repeat(5)
+        .join("\n");
+        let failures = RepetitionConfig::default().failures(&text);
+        assert!(failures.contains(&RepetitionRule::DuplicateLineFraction));
+        assert!(failures.contains(&RepetitionRule::DuplicateLineCharFraction));
+        assert!(failures.contains(&RepetitionRule::TopNGramCharFraction(2)));
+        assert!(failures.contains(&RepetitionRule::DuplicateNGramCharFraction(10)));
+        assert_eq!(
+            RepetitionRule::DuplicateNGramCharFraction(10).as_str(),
+            "duplicate_10_gram_char_fraction"
+        );
+    }
+
+    #[test]
+    fn finds_repeated_paragraphs_in_cleaned_text() {
+        let paragraph =
+            "Our shop offers the best prices for watches. Order today and get free shipping!";
+        let text = [
+            "The history of the city goes back to the Roman period.",
+            paragraph,
+            "A small settlement was founded at the crossing of two trade routes.",
+            paragraph,
+            "Today, the city is known for its old town and its university.",
+            paragraph,
+        ]
+        .join("\n\n");
+        let cleaned = C4CleaningConfig::default().clean(&text).unwrap();
+        assert!(!cleaned.contains("\n\n"));
+        let failures = RepetitionConfig::default().failures(&cleaned);
+        assert!(failures.contains(&RepetitionRule::DuplicateParagraphFraction));
+        assert!(failures.contains(&RepetitionRule::DuplicateParagraphCharFraction));
+    }
+}
@@ -0,0 +1,306 @@
+//! This module contains helper functions to parse the HTTP response that is stored in the body of a WARC `response` record.
+//!
+//! A WARC `response` record contains the raw HTTP response as it was received by the crawler,
+//! i.e. a status line, the response headers and the (possibly encoded) payload.
+//! Common Crawl usually stores the payload already decoded and renames the original
+//! `Content-Encoding` and `Transfer-Encoding` headers to `X-Crawler-Content-Encoding` and `X-Crawler-Transfer-Encoding`.
+//! This is not guaranteed for every record, though, which is why we also look at the `WARC-Identified-Payload-Type`
+//! header before deciding whether the payload still has to be decoded.
+use std::io::Read;
+
+use anyhow::Context;
+
+/// The maximum number of headers that we expect in a single HTTP response.
+const MAX_HEADERS: usize = 256;
+
+/// Payload types that tell us that the payload is still compressed.
+/// Compressed data that the type detection does not recognize, e.g. brotli, is identified as `application/octet-stream`.
+const COMPRESSED_PAYLOAD_TYPES: &[&str] = &[
+    "application/gzip",
+    "application/x-gzip",
+    "application/brotli",
+    "application/x-brotli",
+    "application/zlib",
+    "application/octet-stream",
+];
+
+/// A parsed HTTP response from the body of a WARC `response` record.
+/// The payload has been de-chunked and decompressed if necessary.
+#[derive(Debug, Clone)]
+pub struct HttpResponse {
+    /// The HTTP minor version, e.g. `1` for `HTTP/1.1`.
+    pub version: u8,
+    pub status: u16,
+    pub reason: String,
+    /// The response headers in the order in which they appear in the response.
+    /// Header values that are not valid UTF-8 are decoded lossily.
+    pub headers: Vec<(String, String)>,
+    pub payload: Vec<u8>,
+    /// Whether a content encoding of the payload was undone, in which case the
+    /// `WARC-Identified-Payload-Type` describes the compressed payload instead of the decoded one.
+    pub decompressed: bool,
+    /// Whether the compressed payload ended early and only the part that could be decoded is kept.
+    pub truncated: bool,
+}
+
+impl HttpResponse {
+    /// Returns the value of the first header with the given name.
+    /// Header names are compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Returns the value of the `Content-Type` header, if present.
+    pub fn content_type(&self) -> Option<&str> {
+        self.header("Content-Type")
+    }
+
+    /// Returns the MIME type of the `Content-Type` header without any parameters, in lower case.
+    pub fn mime_type(&self) -> Option<String> {
+        self.content_type().map(mime_essence)
+    }
+}
+
+/// Strips the parameters from a MIME type such as `text/html; charset=utf-8` and lowercases the rest.
+pub fn mime_essence(content_type: &str) -> String {
+    content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase()
+}
+
+/// Splits the body of a WARC `response` record into status line, headers and payload.
+/// The `identified_payload_type` is the value of the `WARC-Identified-Payload-Type` header of the record, if present.
+///
+/// Chunked transfer encoding is undone if the payload is still chunked.
+/// Content encodings (`gzip`, `deflate` and `br`) are undone unless the identified payload type says that
+/// the payload has already been decoded, e.g. because it is `text/html`.
+/// Decoding stops after one byte more than `max_payload_bytes`, see [crate::prefilter::PrefilterConfig].
+pub fn parse_http_response(
+    body: &[u8],
+    identified_payload_type: Option<&str>,
+    max_payload_bytes: usize,
+) -> Result<HttpResponse, anyhow::Error> {
+    let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut headers);
+    let header_length = match httparse::ParserConfig::default()
+        .allow_spaces_after_header_name_in_responses(true)
+        .allow_obsolete_multiline_headers_in_responses(true)
+        .allow_multiple_spaces_in_response_status_delimiters(true)
+        .ignore_invalid_headers_in_responses(true)
+        .parse_response(&mut response, body)
+        .context("Failed to parse HTTP response headers")?
+    {
+        httparse::Status::Complete(length) => length,
+        httparse::Status::Partial => {
+            return Err(anyhow::anyhow!("HTTP response headers are incomplete"))
+        }
+    };
+
+    let mut http_response = HttpResponse {
+        version: response.version.unwrap_or(1),
+        status: response.code.unwrap_or_default(),
+        reason: response.reason.unwrap_or_default().to_string(),
+        headers: response
+            .headers
+            .iter()
+            .map(|h| {
+                (
+                    h.name.to_string(),
+                    String::from_utf8_lossy(h.value).trim().to_string(),
+                )
+            })
+            .collect(),
+        payload: Vec::new(),
+        decompressed: false,
+        truncated: false,
+    };
+
+    let mut payload = body[header_length..].to_vec();
+
+    if header_contains_token(http_response.header("Transfer-Encoding"), "chunked") {
+        // Common Crawl might already have removed the chunking, so we only use the de-chunked payload if that succeeds.
+        match decode_chunked(&payload) {
+            Ok(decoded) => payload = decoded,
+            Err(e) => tracing::debug!("Payload is not chunked despite Transfer-Encoding: {}", e),
+        }
+    }
+
+    if let Some(content_encoding) = http_response.header("Content-Encoding") {
+        if payload_is_compressed(identified_payload_type) {
+            // Encodings are listed in the order in which they were applied, so we undo them in reverse order.
+            let encodings: Vec<_> = content_encoding
+                .split(',')
+                .map(|e| e.trim().to_ascii_lowercase())
+                .collect();
+            for encoding in encodings.iter().rev() {
+                let truncated;
+                (payload, truncated) = decode_content(&payload, encoding, max_payload_bytes)?;
+                http_response.truncated |= truncated;
+            }
+            http_response.decompressed = true;
+        }
+    }
+
+    http_response.payload = payload;
+    Ok(http_response)
+}
+
+/// Checks whether a comma-separated header value contains the given token.
+fn header_contains_token(header: Option<&str>, token: &str) -> bool {
+    header
+        .map(|h| h.split(',').any(|t| t.trim().eq_ignore_ascii_case(token)))
+        .unwrap_or(false)
+}
+
+/// Decides whether the payload still has to be decompressed, trusting `Content-Encoding` if the payload type is unknown.
+fn payload_is_compressed(identified_payload_type: Option<&str>) -> bool {
+    identified_payload_type.is_none_or(|payload_type| {
+        COMPRESSED_PAYLOAD_TYPES.contains(&mime_essence(payload_type).as_str())
+    })
+}
+
+/// Undoes a single content encoding and returns at most `max_bytes + 1` bytes, so that the prefilter can
+/// reject payloads that are too large without decompressing them completely.
+/// A gzip or deflate stream that ends early, e.g. because the WARC record is truncated, is kept as far as
+/// it could be decoded, which is signaled by the second element of the result.
+fn decode_content(
+    payload: &[u8],
+    encoding: &str,
+    max_bytes: usize,
+) -> Result<(Vec<u8>, bool), anyhow::Error> {
+    let decoder: Box<dyn Read + '_> = match encoding {
+        "gzip" | "x-gzip" => Box::new(flate2::read::MultiGzDecoder::new(payload)),
+        "deflate" => Box::new(flate2::read::ZlibDecoder::new(payload)),
+        "br" => Box::new(brotli::Decompressor::new(payload, 4096)),
+        "identity" | "" => return Ok((payload.to_vec(), false)),
+        _ => return Err(anyhow::anyhow!("Unsupported content encoding {}", encoding)),
+    };
+    let mut buffer = Vec::new();
+    match decoder.take(max_bytes as u64 + 1).read_to_end(&mut buffer) {
+        Ok(_) => Ok((buffer, false)),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok((buffer, true)),
+        Err(e) => {
+            Err(anyhow::Error::new(e).context(format!("Failed to decode {} payload", encoding)))
+        }
+    }
+}
+
+/// Removes the chunked transfer encoding from a payload.
+/// Chunk extensions and trailers are ignored.
+fn decode_chunked(payload: &[u8]) -> Result<Vec<u8>, anyhow::Error> {
+    let mut decoded = Vec::with_capacity(payload.len());
+    let mut rest = payload;
+    loop {
+        let line_end = rest
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .context("Missing CRLF after chunk size")?;
+        let size_line =
+            std::str::from_utf8(&rest[..line_end]).context("Chunk size is not ASCII")?;
+        let size_str = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_str, 16)
+            .with_context(|| format!("Invalid chunk size {:?}", size_str))?;
+        rest = &rest[line_end + 2..];
+        if size == 0 {
+            return Ok(decoded);
+        }
+        if rest.len() < size {
+            return Err(anyhow::anyhow!("Chunk is truncated"));
+        }
+        decoded.extend_from_slice(&rest[..size]);
+        rest = rest[size..].strip_prefix(b"\r\n").unwrap_or(&rest[size..]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::parse_http_response;
+
+    const MAX_BYTES: usize = 1 << 20;
+
+    #[test]
+    fn can_parse_response_with_crlf_headers() {
+        let body = b"HTTP/1.1 200 OK\r\nContent-Type: text/html; charset=UTF-8\r\nX-Crawler-Content-Encoding: gzip\r\n\r\n<html>Hello</html>";
+        let response = parse_http_response(body, Some("text/html"), MAX_BYTES).unwrap();
+        assert_eq!(response.status, 200);
+        assert_eq!(response.reason, "OK");
+        assert_eq!(response.mime_type().as_deref(), Some("text/html"));
+        assert_eq!(response.payload, b"<html>Hello</html>");
+    }
+
+    #[test]
+    fn can_decode_chunked_payload() {
+        let body = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nHello\r\n7;ext=1\r\n, World\r\n0\r\n\r\n";
+        let response = parse_http_response(body, Some("text/html"), MAX_BYTES).unwrap();
+        assert_eq!(response.payload, b"Hello, World");
+    }
+
+    #[test]
+    fn only_decodes_gzip_if_payload_is_still_compressed() {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(b"<html>Hello</html>").unwrap();
+        let mut body = b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\n\r\n".to_vec();
+        body.extend_from_slice(&encoder.finish().unwrap());
+
+        let response = parse_http_response(&body, Some("application/gzip"), MAX_BYTES).unwrap();
+        assert_eq!(response.payload, b"<html>Hello</html>");
+
+        let response = parse_http_response(&body, None, MAX_BYTES).unwrap();
+        assert_eq!(response.payload, b"<html>Hello</html>");
+
+        let plain = b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\n\r\n<html>Hello</html>";
+        let response = parse_http_response(plain, Some("text/html"), MAX_BYTES).unwrap();
+        assert_eq!(response.payload, b"<html>Hello</html>");
+    }
+
+    #[test]
+    fn decodes_brotli_and_deflate_without_identified_payload_type() {
+        let html = b"<html>Hello</html>";
+        let mut brotli = Vec::new();
+        brotli::CompressorWriter::new(&mut brotli, 4096, 5, 22)
+            .write_all(html)
+            .unwrap();
+        let mut deflate =
+            flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        deflate.write_all(html).unwrap();
+        for (encoding, payload) in [("br", brotli), ("deflate", deflate.finish().unwrap())] {
+            let mut body =
+                format!("HTTP/1.1 200 OK\r\nContent-Encoding: {}\r\n\r\n", encoding).into_bytes();
+            body.extend_from_slice(&payload);
+            for identified_payload_type in [None, Some("application/octet-stream")] {
+                let response =
+                    parse_http_response(&body, identified_payload_type, MAX_BYTES).unwrap();
+                assert_eq!(response.payload, html, "{}", encoding);
+                assert!(response.decompressed);
+            }
+        }
+    }
+
+    #[test]
+    fn limits_and_keeps_truncated_payloads() {
+        let html: Vec<u8> = b"<html>".iter().chain(&[b'a'; 10_000]).copied().collect();
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&html).unwrap();
+        let compressed = encoder.finish().unwrap();
+        let mut body = b"HTTP/1.1 200 OK\r\nContent-Encoding: gzip\r\n\r\n".to_vec();
+        body.extend_from_slice(&compressed);
+
+        let response = parse_http_response(&body, None, 100).unwrap();
+        assert_eq!(response.payload, &html[..101]);
+        assert!(!response.truncated);
+
+        let response =
+            parse_http_response(&body[..body.len() - compressed.len() / 2], None, MAX_BYTES)
+                .unwrap();
+        assert!(response.truncated);
+        assert!(html.starts_with(&response.payload));
+    }
+}
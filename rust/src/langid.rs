@@ -0,0 +1,386 @@
+//! This module contains a language identifier for the extracted text.
+//!
+//! The cdx `languages` field is computed on the raw page including navigation and boilerplate,
+//! so it is often wrong for the main text. Instead, the worker identifies the language of the extracted text,
+//! by default with the model that ships with the [whatlang] crate, which knows 69 languages,
+//! or with a [LanguageModel] that is loaded from a local file.
+//!
+//! The [LanguageModel] is a naive Bayes classifier over character n-grams: every language has the log-probabilities
+//! of its most frequent n-grams, and the language whose n-grams explain the text best wins.
+//! The confidence of a prediction is the softmax over the mean log-probability per n-gram of every language,
+//! divided by the temperature of the model. The posterior of the summed log-probabilities would be almost 1 for every
+//! text of more than a few words, even for mixed or ambiguous ones.
+//! Models are trained from plain text samples with the `train_langid` binary and stored as JSON.
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+
+use crate::filters::FilterFailure;
+
+lazy_static! {
+    static ref IDENTIFIED_LANGUAGES_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "identified_languages",
+        "Number of documents per identified language",
+        &["language"]
+    )
+    .unwrap();
+}
+
+/// Only the beginning of long documents is scored, which is plenty to identify the language.
+const MAX_SCORED_CHARS: usize = 10_000;
+
+/// The temperature of the softmax over the mean log-probabilities per n-gram, if the model file does not set one.
+pub const DEFAULT_TEMPERATURE: f64 = 0.1;
+
+fn default_temperature() -> f64 {
+    DEFAULT_TEMPERATURE
+}
+
+/// The language of a text and the confidence of the prediction between 0 and 1.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LanguagePrediction {
+    pub language: String,
+    pub confidence: f64,
+}
+
+/// The n-gram statistics of a single language.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct LanguageProfile {
+    /// Log-probabilities of the n-grams of the language.
+    ngrams: HashMap<String, f64>,
+    /// Log-probability of n-grams that are not in `ngrams`.
+    unseen: f64,
+}
+
+/// A naive Bayes language model over character n-grams.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LanguageModel {
+    /// The model uses all n-grams with 1 to `max_n` characters.
+    max_n: usize,
+    /// The profiles per language label, e.g. ISO 639-3 codes such as `eng` like in the cdx index.
+    languages: BTreeMap<String, LanguageProfile>,
+    /// The temperature of the softmax that computes the confidence.
+    /// Lower temperatures make the confidence of clear-cut predictions approach 1 faster.
+    #[serde(default = "default_temperature")]
+    temperature: f64,
+}
+
+impl LanguageModel {
+    /// Trains a model from text samples per language.
+    /// Only the `max_ngrams` most frequent n-grams of every language are kept, which bounds the size of the model.
+    pub fn train<'a>(
+        samples: impl IntoIterator<Item = (&'a str, &'a str)>,
+        max_n: usize,
+        max_ngrams: usize,
+        temperature: f64,
+    ) -> Self {
+        let mut counts: BTreeMap<String, HashMap<String, usize>> = BTreeMap::new();
+        for (language, text) in samples {
+            let language_counts = counts.entry(language.to_string()).or_default();
+            for ngram in ngrams(text, max_n) {
+                *language_counts.entry(ngram).or_default() += 1;
+            }
+        }
+        let languages = counts
+            .into_iter()
+            .map(|(language, counts)| {
+                let mut counts: Vec<_> = counts.into_iter().collect();
+                counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+                counts.truncate(max_ngrams);
+                // Add-one smoothing over the kept n-grams and one bucket for all unseen n-grams.
+                let total = counts.iter().map(|(_, count)| count).sum::<usize>() + counts.len() + 1;
+                let total = total as f64;
+                let profile = LanguageProfile {
+                    unseen: (1.0 / total).ln(),
+                    ngrams: counts
+                        .into_iter()
+                        .map(|(ngram, count)| (ngram, ((count + 1) as f64 / total).ln()))
+                        .collect(),
+                };
+                (language, profile)
+            })
+            .collect();
+        Self {
+            max_n,
+            languages,
+            temperature,
+        }
+    }
+
+    /// Loads a model that was written with [LanguageModel::save].
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open language model {}", path.display()))?;
+        let model: Self = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("Failed to parse language model {}", path.display()))?;
+        anyhow::ensure!(
+            model.temperature > 0.0,
+            "The temperature of language model {} has to be positive",
+            path.display()
+        );
+        Ok(model)
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create language model {}", path.display()))?;
+        serde_json::to_writer(BufWriter::new(file), self)?;
+        Ok(())
+    }
+
+    /// The language labels that the model knows.
+    pub fn languages(&self) -> impl Iterator<Item = &str> {
+        self.languages.keys().map(String::as_str)
+    }
+
+    /// Returns the most likely language of `text`, or `None` if the text contains no letters
+    /// or the model contains no languages.
+    pub fn identify(&self, text: &str) -> Option<LanguagePrediction> {
+        let ngrams = ngrams(scored_prefix(text), self.max_n);
+        if ngrams.is_empty() {
+            return None;
+        }
+        let scores: Vec<(&String, f64)> = self
+            .languages
+            .iter()
+            .map(|(language, profile)| {
+                let score: f64 = ngrams
+                    .iter()
+                    .map(|ngram| profile.ngrams.get(ngram).copied().unwrap_or(profile.unseen))
+                    .sum();
+                (language, score / ngrams.len() as f64 / self.temperature)
+            })
+            .collect();
+        let (language, best) = scores.iter().max_by(|(_, a), (_, b)| a.total_cmp(b))?;
+        // The softmax of the best language, computed relative to its score to avoid overflow.
+        let normalizer: f64 = scores.iter().map(|(_, score)| (score - best).exp()).sum();
+        Some(LanguagePrediction {
+            language: language.to_string(),
+            confidence: 1.0 / normalizer,
+        })
+    }
+}
+
+/// Returns the first [MAX_SCORED_CHARS] characters of the text.
+fn scored_prefix(text: &str) -> &str {
+    let end = text
+        .char_indices()
+        .nth(MAX_SCORED_CHARS)
+        .map_or(text.len(), |(i, _)| i);
+    &text[..end]
+}
+
+/// Identifies the language with the built-in model of [whatlang]. Its languages are labelled with ISO 639-3 codes
+/// like the cdx index. Returns `None` if the text contains no letters.
+fn identify_with_whatlang(text: &str) -> Option<LanguagePrediction> {
+    let info = whatlang::detect(scored_prefix(text))?;
+    Some(LanguagePrediction {
+        language: info.lang().code().to_string(),
+        confidence: info.confidence(),
+    })
+}
+
+/// Returns the character n-grams with 1 to `max_n` characters of all words of the text.
+/// Words are lowercased sequences of letters, padded with a space on both sides to mark their boundaries.
+fn ngrams(text: &str, max_n: usize) -> Vec<String> {
+    let mut ngrams = Vec::new();
+    for word in text
+        .split(|c: char| !c.is_alphabetic())
+        .filter(|word| !word.is_empty())
+    {
+        let chars: Vec<char> = format!(" {} ", word.to_lowercase()).chars().collect();
+        for n in 1..=max_n {
+            ngrams.extend(
+                chars
+                    .windows(n)
+                    .map(|ngram| ngram.iter().collect::<String>())
+                    .filter(|ngram| ngram != " "),
+            );
+        }
+    }
+    ngrams
+}
+
+/// Configuration of the language identification stage.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LanguageIdConfig {
+    /// Path to a model that was trained with `train_langid`. If not set, the built-in model of whatlang is used.
+    pub model_path: Option<PathBuf>,
+    /// Documents in other languages are dropped. An empty list keeps all languages.
+    pub target_languages: Vec<String>,
+    /// Documents whose language was identified with a lower confidence are dropped.
+    pub min_confidence: f64,
+}
+
+impl Default for LanguageIdConfig {
+    fn default() -> Self {
+        Self {
+            model_path: None,
+            target_languages: Vec::new(),
+            min_confidence: 0.65,
+        }
+    }
+}
+
+/// Identifies the language of documents and checks it against the target languages.
+pub struct LanguageFilter {
+    /// The trained model, or `None` for the built-in model of whatlang.
+    model: Option<LanguageModel>,
+    target_languages: Vec<String>,
+    min_confidence: f64,
+}
+
+impl LanguageFilter {
+    /// Loads the model of the config, or uses the built-in model of whatlang if none is configured.
+    pub fn from_config(config: &LanguageIdConfig) -> Result<Self, anyhow::Error> {
+        let model = config
+            .model_path
+            .as_ref()
+            .map(|path| LanguageModel::load(path))
+            .transpose()?;
+        match &model {
+            Some(model) => tracing::info!(
+                "Loaded language model with languages {:?}",
+                model.languages().collect::<Vec<_>>()
+            ),
+            None => tracing::info!("Using the built-in language model of whatlang"),
+        }
+        Ok(Self {
+            model,
+            target_languages: config.target_languages.clone(),
+            min_confidence: config.min_confidence,
+        })
+    }
+
+    /// Identifies the language of `text` and returns the prediction together with the rule that
+    /// the document fails, if it is not in a target language or if the confidence is too low.
+    pub fn check(&self, text: &str) -> (Option<LanguagePrediction>, Option<FilterFailure>) {
+        let failure = |rule| {
+            Some(FilterFailure {
+                filter: "language_id",
+                rule,
+            })
+        };
+        let prediction = match &self.model {
+            Some(model) => model.identify(text),
+            None => identify_with_whatlang(text),
+        };
+        let Some(prediction) = prediction else {
+            return (None, failure("unknown_language"));
+        };
+        IDENTIFIED_LANGUAGES_COUNTER
+            .with_label_values(&[&prediction.language])
+            .inc();
+        let failure = if !self.target_languages.is_empty()
+            && !self.target_languages.contains(&prediction.language)
+        {
+            failure("target_language")
+        } else if prediction.confidence < self.min_confidence {
+            failure("min_confidence")
+        } else {
+            None
+        };
+        (Some(prediction), failure)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LanguageFilter, LanguageIdConfig, LanguageModel, DEFAULT_TEMPERATURE};
+
+    const SAMPLES: &[(&str, &str)] = &[
+        ("eng", "The weather is nice today and we are going to the park with the children. They want to play football and eat ice cream. In the evening, we will have dinner with our friends."),
+        ("deu", "Das Wetter ist heute schön und wir gehen mit den Kindern in den Park. Sie wollen Fußball spielen und Eis essen. Am Abend essen wir mit unseren Freunden zu Abend."),
+        ("fra", "Il fait beau aujourd'hui et nous allons au parc avec les enfants. Ils veulent jouer au football et manger une glace. Le soir, nous dînerons avec nos amis."),
+    ];
+
+    #[test]
+    fn identifies_languages() {
+        let model = LanguageModel::train(SAMPLES.iter().copied(), 3, 1000, DEFAULT_TEMPERATURE);
+        let prediction = model
+            .identify("Where are the children? They are playing in the garden.")
+            .unwrap();
+        assert_eq!(prediction.language, "eng");
+        assert!(prediction.confidence > 0.9);
+        assert_eq!(
+            model
+                .identify("Wo sind die Kinder? Sie spielen im Garten.")
+                .unwrap()
+                .language,
+            "deu"
+        );
+        assert_eq!(
+            model
+                .identify("Où sont les enfants? Ils jouent dans le jardin.")
+                .unwrap()
+                .language,
+            "fra"
+        );
+        assert_eq!(model.identify("1234 ..."), None);
+
+        let filter = LanguageFilter {
+            model: Some(model),
+            target_languages: vec!["deu".to_string()],
+            min_confidence: 0.5,
+        };
+        let (prediction, failure) = filter.check("The children are playing football in the park.");
+        assert_eq!(prediction.unwrap().language, "eng");
+        assert_eq!(failure.unwrap().rule, "target_language");
+    }
+
+    #[test]
+    fn mixed_text_has_low_confidence() {
+        let filter = LanguageFilter {
+            model: Some(LanguageModel::train(
+                SAMPLES.iter().copied(),
+                3,
+                1000,
+                DEFAULT_TEMPERATURE,
+            )),
+            target_languages: Vec::new(),
+            min_confidence: LanguageIdConfig::default().min_confidence,
+        };
+        for mixed in [
+            "The children spielen im Park.",
+            "Les enfants and the children im Garten.",
+        ] {
+            let (prediction, failure) = filter.check(mixed);
+            assert!(prediction.unwrap().confidence < filter.min_confidence);
+            assert_eq!(failure.unwrap().rule, "min_confidence");
+        }
+        let (_, failure) = filter.check("Wo sind die Kinder? Sie spielen im Garten.");
+        assert_eq!(failure, None);
+    }
+
+    #[test]
+    fn identifies_languages_with_builtin_model() {
+        let filter = LanguageFilter::from_config(&LanguageIdConfig {
+            target_languages: vec!["deu".to_string()],
+            ..Default::default()
+        })
+        .unwrap();
+        let (prediction, failure) = filter.check(
+            "Das Wetter ist heute schön und wir gehen mit den Kindern in den Park. Sie wollen Fußball spielen und Eis essen.",
+        );
+        let prediction = prediction.unwrap();
+        assert_eq!(prediction.language, "deu");
+        assert!(prediction.confidence > 0.9, "{:?}", prediction);
+        assert_eq!(failure, None);
+        let (prediction, failure) = filter
+            .check("The weather is nice today and we are going to the park with the children.");
+        assert_eq!(prediction.unwrap().language, "eng");
+        assert_eq!(failure.unwrap().rule, "target_language");
+        let (prediction, failure) = filter.check("1234 ...");
+        assert_eq!(prediction, None);
+        assert_eq!(failure.unwrap().rule, "unknown_language");
+    }
+}
@@ -1,5 +1,24 @@
 //! This crate consists of two binaries, called [batcher](../batcher/index.html) and [worker](../worker/index.html)
+pub mod blocklist;
+pub mod bloom;
+pub mod cleaning;
 pub mod commoncrawl;
+pub mod crawls;
+pub mod encoding;
+pub mod extractor;
+pub mod filters;
+pub mod http;
+pub mod langid;
+pub mod minhash;
+pub mod output;
+pub mod paragraph_dedup;
+pub mod perplexity;
+pub mod pii;
+pub mod prefilter;
 pub mod rabbitmq;
+pub mod readability;
+pub mod stages;
+pub mod tokenizer;
 pub mod tracing_and_metrics;
 pub mod trafilatura;
+pub mod trafilatura_pool;
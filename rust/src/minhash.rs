@@ -0,0 +1,288 @@
+//! This module contains MinHash signatures and locality-sensitive hashing (LSH) to find near-duplicate documents.
+//!
+//! Exact digests miss copies of the same article that differ only in boilerplate. MinHash estimates the Jaccard
+//! similarity of the sets of word n-grams (shingles) of two documents: for each of several hash functions,
+//! the signature contains the minimum hash over all shingles, and the fraction of equal signature entries
+//! approximates the similarity.
+//!
+//! To avoid comparing all pairs of documents, the signature is split into bands. Documents that agree on all
+//! entries of at least one band end up in the same LSH bucket and become candidate duplicates.
+//! With `b` bands of `r` rows, two documents with similarity `s` become candidates with probability `1 - (1 - s^r)^b`.
+//!
+//! The worker writes one signature shard of [SignatureRecord]s next to every output shard. Documents without words
+//! have no shingles and no signature, since they would all collide in every band.
+//! The `dedup` binary reads them, clusters the duplicates with [find_duplicate_clusters] and decides which
+//! document of every cluster is kept.
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use twox_hash::XxHash64;
+
+use crate::output::OutputRecord;
+
+/// The file extension of signature shards.
+pub const SIGNATURE_SHARD_EXTENSION: &str = "minhash.jsonl.gz";
+
+/// The Mersenne prime 2^61 - 1, the modulus of the hash permutations.
+const MERSENNE_PRIME: u64 = (1 << 61) - 1;
+
+/// Configuration of the MinHash stage.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MinHashConfig {
+    /// Whether the worker writes signature shards.
+    pub enabled: bool,
+    /// Number of words per shingle.
+    pub shingle_size: usize,
+    /// Number of hash functions, i.e. the length of a signature.
+    pub num_permutations: usize,
+    /// Seed from which the hash functions are derived. Signatures are only comparable if they use the same seed.
+    pub seed: u64,
+}
+
+impl Default for MinHashConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            shingle_size: 5,
+            num_permutations: 112,
+            seed: 1,
+        }
+    }
+}
+
+/// The MinHash signature of a document in an output shard.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SignatureRecord {
+    /// The id of the document, see [OutputRecord::id].
+    pub id: String,
+    pub url: String,
+    /// The number of characters of the text. Among duplicates, the longest document is kept.
+    pub text_length: usize,
+    pub signature: Vec<u32>,
+}
+
+/// Computes MinHash signatures with a fixed set of hash functions.
+#[derive(Debug, Clone)]
+pub struct MinHasher {
+    shingle_size: usize,
+    seed: u64,
+    /// The coefficients `(a, b)` of the hash functions `(a * h + b) mod p`.
+    permutations: Vec<(u64, u64)>,
+}
+
+impl MinHasher {
+    pub fn new(config: &MinHashConfig) -> Self {
+        let mut state = config.seed;
+        let permutations = (0..config.num_permutations)
+            .map(|_| {
+                let a = splitmix64(&mut state) % (MERSENNE_PRIME - 1) + 1;
+                let b = splitmix64(&mut state) % MERSENNE_PRIME;
+                (a, b)
+            })
+            .collect();
+        Self {
+            shingle_size: config.shingle_size.max(1),
+            seed: config.seed,
+            permutations,
+        }
+    }
+
+    /// Computes the signature of `text`. The text is lowercased and split into words without punctuation first.
+    /// Texts with fewer words than the shingle size are treated as a single shingle.
+    /// Returns `None` if the text contains no words.
+    pub fn signature(&self, text: &str) -> Option<Vec<u32>> {
+        let lowercase = text.to_lowercase();
+        let words: Vec<&str> = lowercase
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .collect();
+        if words.is_empty() {
+            return None;
+        }
+        let shingle_size = self.shingle_size.min(words.len());
+        let mut signature = vec![u64::MAX; self.permutations.len()];
+        for shingle in words.windows(shingle_size) {
+            let hash = XxHash64::oneshot(self.seed, shingle.join(" ").as_bytes()) % MERSENNE_PRIME;
+            for (min, (a, b)) in signature.iter_mut().zip(&self.permutations) {
+                let value = mul_add_mod(*a, hash, *b);
+                if value < *min {
+                    *min = value;
+                }
+            }
+        }
+        Some(signature.into_iter().map(|value| value as u32).collect())
+    }
+
+    /// Computes the signature record of a document in an output shard.
+    /// Returns `None` if the text contains no words.
+    pub fn signature_record(&self, record: &OutputRecord) -> Option<SignatureRecord> {
+        Some(SignatureRecord {
+            id: record.id(),
+            url: record.url.clone(),
+            text_length: record.document.text.chars().count(),
+            signature: self.signature(&record.document.text)?,
+        })
+    }
+}
+
+/// A step of the splitmix64 generator, which derives the hash coefficients from the seed.
+fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E3779B97F4A7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+fn mul_add_mod(a: u64, x: u64, b: u64) -> u64 {
+    ((a as u128 * x as u128 + b as u128) % MERSENNE_PRIME as u128) as u64
+}
+
+/// Estimates the Jaccard similarity of two documents from their signatures.
+pub fn estimated_similarity(a: &[u32], b: &[u32]) -> f64 {
+    if a.is_empty() || a.len() != b.len() {
+        return 0.0;
+    }
+    let equal = a.iter().zip(b).filter(|(a, b)| a == b).count();
+    equal as f64 / a.len() as f64
+}
+
+/// Returns the hashes of the bands of a signature. The length of the signature has to be a multiple of `bands`,
+/// see [check_bands].
+pub fn band_hashes(signature: &[u32], bands: usize) -> Vec<u64> {
+    signature
+        .chunks_exact(signature.len() / bands)
+        .enumerate()
+        .map(|(band, rows)| {
+            let bytes: Vec<u8> = rows.iter().flat_map(|row| row.to_le_bytes()).collect();
+            XxHash64::oneshot(band as u64, &bytes)
+        })
+        .collect()
+}
+
+/// Checks that signatures with `num_permutations` entries can be split into `bands` bands of equal size.
+pub fn check_bands(num_permutations: usize, bands: usize) -> Result<(), anyhow::Error> {
+    anyhow::ensure!(
+        bands > 0 && num_permutations >= bands && num_permutations.is_multiple_of(bands),
+        "Signatures with {} permutations cannot be split into {} bands of equal size",
+        num_permutations,
+        bands
+    );
+    Ok(())
+}
+
+/// A union-find structure over document indices.
+struct DisjointSet {
+    parents: Vec<usize>,
+}
+
+impl DisjointSet {
+    fn new(size: usize) -> Self {
+        Self {
+            parents: (0..size).collect(),
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parents[i] != i {
+            self.parents[i] = self.parents[self.parents[i]];
+            i = self.parents[i];
+        }
+        i
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let (a, b) = (self.find(a), self.find(b));
+        if a != b {
+            self.parents[a.max(b)] = a.min(b);
+        }
+    }
+}
+
+/// Groups the documents into clusters of near-duplicates and returns all clusters with more than one document,
+/// as indices into `signatures`.
+/// Documents that share an LSH bucket are only merged if their estimated similarity is at least `min_similarity`,
+/// which removes most false positives of the banding.
+/// Fails if the signatures have different lengths or cannot be split into `bands` bands, see [check_bands].
+pub fn find_duplicate_clusters(
+    signatures: &[&[u32]],
+    bands: usize,
+    min_similarity: f64,
+) -> Result<Vec<Vec<usize>>, anyhow::Error> {
+    if let Some(first) = signatures.first() {
+        anyhow::ensure!(
+            signatures
+                .iter()
+                .all(|signature| signature.len() == first.len()),
+            "The signatures have different numbers of permutations"
+        );
+        check_bands(first.len(), bands)?;
+    }
+    let mut set = DisjointSet::new(signatures.len());
+    let mut buckets: HashMap<(usize, u64), usize> = HashMap::new();
+    for (i, signature) in signatures.iter().enumerate() {
+        for (band, hash) in band_hashes(signature, bands).into_iter().enumerate() {
+            match buckets.get(&(band, hash)) {
+                Some(&first) => {
+                    if estimated_similarity(signatures[first], signature) >= min_similarity {
+                        set.union(first, i);
+                    }
+                }
+                None => {
+                    buckets.insert((band, hash), i);
+                }
+            }
+        }
+    }
+    let mut clusters: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..signatures.len() {
+        clusters.entry(set.find(i)).or_default().push(i);
+    }
+    let mut clusters: Vec<Vec<usize>> = clusters
+        .into_values()
+        .filter(|cluster| cluster.len() > 1)
+        .collect();
+    clusters.sort();
+    Ok(clusters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        check_bands, estimated_similarity, find_duplicate_clusters, MinHashConfig, MinHasher,
+    };
+
+    #[test]
+    fn finds_near_duplicates() {
+        let hasher = MinHasher::new(&MinHashConfig::default());
+        let article = "The city library reopened on Monday after a renovation that took almost two years. The building now has a new reading room, a cafe and a children's area on the ground floor. Visitors can borrow books, games and musical instruments with their library card.";
+        let texts = [
+            article.to_string(),
+            format!("Home | News | Sport\n{}\nShare this article", article),
+            "A completely different text about the weather, which is going to be sunny and warm for the rest of the week, with temperatures of up to thirty degrees in the afternoon.".to_string(),
+            article.to_string(),
+        ];
+        let signatures: Vec<Vec<u32>> = texts
+            .iter()
+            .map(|text| hasher.signature(text).unwrap())
+            .collect();
+        assert_eq!(signatures[0].len(), 112);
+        assert!(estimated_similarity(&signatures[0], &signatures[1]) > 0.7);
+        assert!(estimated_similarity(&signatures[0], &signatures[2]) < 0.1);
+
+        let signatures: Vec<&[u32]> = signatures.iter().map(Vec::as_slice).collect();
+        assert_eq!(
+            find_duplicate_clusters(&signatures, 14, 0.5).unwrap(),
+            vec![vec![0, 1, 3]]
+        );
+        assert!(find_duplicate_clusters(&signatures, 15, 0.5).is_err());
+        assert!(find_duplicate_clusters(&signatures[..2], 0, 0.5).is_err());
+
+        // Texts without words have no signature instead of one that collides with all others.
+        assert_eq!(hasher.signature(""), None);
+        assert_eq!(hasher.signature("... | ---"), None);
+        assert!(check_bands(112, 14).is_ok());
+        assert!(check_bands(112, 113).is_err());
+    }
+}
@@ -0,0 +1,199 @@
+//! This module contains the output format of the worker and helper functions to write and read output shards.
+//!
+//! The worker writes one shard per batch. A shard is a gzip-compressed JSON lines file
+//! in which every line is an [OutputRecord].
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    commoncrawl::CdxEntry, extractor::ExtractedDocument, perplexity::QualityTier, pii::PiiSpan,
+};
+
+/// The file extension of output shards.
+pub const SHARD_EXTENSION: &str = "jsonl.gz";
+
+/// The language directory of records whose language is unknown, if the output is split by language.
+pub const UNKNOWN_LANGUAGE: &str = "unknown";
+
+/// A document that the worker writes to its output.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct OutputRecord {
+    /// The URL from the cdx index.
+    pub url: String,
+    /// The crawl timestamp from the cdx index.
+    pub timestamp: String,
+    pub warc_filename: String,
+    pub warc_offset: usize,
+    pub warc_length: usize,
+    #[serde(flatten)]
+    pub document: ExtractedDocument,
+    /// The quality filter rules that the document failed, e.g. `gopher.min_words`.
+    /// Only set if the quality filters run in annotate mode.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub quality_failures: Vec<String>,
+    /// The language of the text, if the `language_id` stage runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub language_confidence: Option<f64>,
+    /// The locations of the placeholders of redacted personal data in the text.
+    /// Only set if the worker is configured to record them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pii_spans: Vec<PiiSpan>,
+    /// The number of tokens of the text, if a tokenizer is configured.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_tokens: Option<usize>,
+    /// The token IDs of the text followed by the EOS token, if one is configured,
+    /// which the worker writes to the token shard instead of the output.
+    #[serde(skip)]
+    pub token_ids: Option<Vec<u32>>,
+    /// The perplexity of the text under the language model of its language, if perplexity scoring is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub perplexity: Option<f64>,
+    /// The perplexities of all paragraphs of the text that contain words, if the worker is configured to record them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub paragraph_perplexities: Vec<f64>,
+    /// The quality tier of the document by its perplexity.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quality_tier: Option<QualityTier>,
+    /// Additional results of custom stages of the document pipeline.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, serde_json::Value>,
+}
+
+impl OutputRecord {
+    pub fn new(entry: &CdxEntry, document: ExtractedDocument) -> Self {
+        Self {
+            url: entry.metadata.url.clone(),
+            timestamp: entry.timestamp.clone(),
+            warc_filename: entry.metadata.filename.clone(),
+            warc_offset: entry.metadata.offset,
+            warc_length: entry.metadata.length,
+            document,
+            quality_failures: Vec::new(),
+            language: None,
+            language_confidence: None,
+            pii_spans: Vec::new(),
+            num_tokens: None,
+            token_ids: None,
+            perplexity: None,
+            paragraph_perplexities: Vec::new(),
+            quality_tier: None,
+            annotations: BTreeMap::new(),
+        }
+    }
+
+    /// A unique id of the document, derived from the location of its WARC record.
+    pub fn id(&self) -> String {
+        format!("{}:{}", self.warc_filename, self.warc_offset)
+    }
+}
+
+/// Returns the name of the shard for a batch, without extension.
+/// The name is derived from the WARC file and offset of the first entry, which makes it
+/// unique per batch and stable if the same batch is processed again.
+/// Returns `None` for empty batches.
+pub fn shard_name(batch: &[CdxEntry]) -> Option<String> {
+    let first = batch.first()?;
+    let warc_name = first
+        .metadata
+        .filename
+        .rsplit('/')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(".warc.gz");
+    Some(format!("{}-{}", warc_name, first.metadata.offset))
+}
+
+/// Writes the records to `path` as gzip-compressed JSON lines and creates missing parent directories.
+/// Besides [OutputRecord]s, this is also used for other shards such as MinHash signatures.
+/// The records are first written to a temporary file which is renamed afterwards,
+/// so that readers never see partially written shards.
+pub fn write_shard<T: Serialize>(path: &Path, records: &[T]) -> Result<(), anyhow::Error> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create output directory {}", parent.display()))?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let file = File::create(&tmp_path)
+        .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+    let mut writer =
+        flate2::write::GzEncoder::new(BufWriter::new(file), flate2::Compression::default());
+    for record in records {
+        serde_json::to_writer(&mut writer, record)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.finish()?.flush()?;
+    std::fs::rename(&tmp_path, path)
+        .with_context(|| format!("Failed to move shard to {}", path.display()))?;
+    Ok(())
+}
+
+/// Reads all records of a shard that was written with [write_shard].
+pub fn read_shard<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, anyhow::Error> {
+    let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    BufReader::new(flate2::read::MultiGzDecoder::new(file))
+        .lines()
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect::<Result<Vec<_>, anyhow::Error>>()
+        .with_context(|| format!("Failed to read shard {}", path.display()))
+}
+
+/// Returns all files below `dir` whose name ends with `.{extension}`, in sorted order.
+pub fn find_shards(dir: &Path, extension: &str) -> Result<Vec<PathBuf>, anyhow::Error> {
+    let suffix = format!(".{}", extension);
+    let mut shards = Vec::new();
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        for entry in
+            std::fs::read_dir(&dir).with_context(|| format!("Failed to read {}", dir.display()))?
+        {
+            let path = entry?.path();
+            if path.is_dir() {
+                dirs.push(path);
+            } else if path.to_string_lossy().ends_with(&suffix) {
+                shards.push(path);
+            }
+        }
+    }
+    shards.sort();
+    Ok(shards)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{commoncrawl::parse_cdx_line, extractor::ExtractedDocument};
+
+    use super::{read_shard, shard_name, write_shard, OutputRecord};
+
+    #[test]
+    fn can_write_and_read_shard() {
+        let entry = parse_cdx_line(
+            r#"0,100,59,139)/ 20240723213521 {"url": "https://139.59.100.0/", "mime": "text/html", "status": "200", "digest": "5JOQMMSNM6N7UCLGGYXDSPSB3FYAQS2C", "length": "16650", "offset": "64016172", "filename": "crawl-data/CC-MAIN-2024-30/segments/1720763518115.82/warc/CC-MAIN-20240723194208-20240723224208-00279.warc.gz", "charset": "UTF-8", "languages": "ind,eng"}"#,
+        );
+        let name = shard_name(std::slice::from_ref(&entry)).unwrap();
+        assert_eq!(name, "CC-MAIN-20240723194208-20240723224208-00279-64016172");
+
+        let record = OutputRecord::new(
+            &entry,
+            ExtractedDocument {
+                text: "Hello".to_string(),
+                title: Some("Title".to_string()),
+                ..Default::default()
+            },
+        );
+        let path = std::env::temp_dir()
+            .join(format!("pipeline-test-{}", std::process::id()))
+            .join(format!("{}.jsonl.gz", name));
+        write_shard(&path, std::slice::from_ref(&record)).unwrap();
+        assert_eq!(read_shard::<OutputRecord>(&path).unwrap(), vec![record]);
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,245 @@
+//! This module removes paragraphs that occur in many documents, such as navigation menus, cookie banners and legal footers.
+//!
+//! Document-level deduplication keeps such boilerplate because the rest of the documents differs.
+//! Instead, every paragraph, i.e. every line of the extracted text, is normalized and hashed, and
+//! a [ParagraphCounter] counts in how many documents every hash occurs. Because the number of distinct
+//! paragraphs of a corpus does not fit into memory, the counter appends the hashes to bucket files on disk
+//! and counts one bucket at a time. Only the hashes of frequent paragraphs are kept in memory, and
+//! [remove_frequent_paragraphs] removes them from the documents.
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use twox_hash::XxHash64;
+
+use crate::output::OutputRecord;
+
+/// Returns the hash of the normalized paragraph, or `None` if it contains no letters or digits.
+/// Normalization lowercases the paragraph, removes punctuation and replaces all digits with zero,
+/// so that e.g. copyright notices with different years are identical.
+pub fn paragraph_hash(paragraph: &str) -> Option<u64> {
+    let normalized = paragraph
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            word.chars()
+                .flat_map(char::to_lowercase)
+                .map(|c| if c.is_numeric() { '0' } else { c })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join(" ");
+    (!normalized.is_empty()).then(|| XxHash64::oneshot(0, normalized.as_bytes()))
+}
+
+/// Counts the number of documents in which every paragraph occurs, with bounded memory.
+pub struct ParagraphCounter {
+    bucket_paths: Vec<PathBuf>,
+    buckets: Vec<BufWriter<File>>,
+}
+
+impl ParagraphCounter {
+    /// Creates a counter that stores its buckets in `work_dir`.
+    /// Every bucket has to fit into memory when the counts are computed, so more documents need more buckets.
+    pub fn new(work_dir: &Path, num_buckets: usize) -> Result<Self, anyhow::Error> {
+        std::fs::create_dir_all(work_dir)
+            .with_context(|| format!("Failed to create {}", work_dir.display()))?;
+        let bucket_paths: Vec<PathBuf> = (0..num_buckets.max(1))
+            .map(|i| work_dir.join(format!("paragraphs-{:05}.bin", i)))
+            .collect();
+        let buckets = bucket_paths
+            .iter()
+            .map(|path| {
+                let file = File::create(path)
+                    .with_context(|| format!("Failed to create {}", path.display()))?;
+                Ok(BufWriter::new(file))
+            })
+            .collect::<Result<_, anyhow::Error>>()?;
+        Ok(Self {
+            bucket_paths,
+            buckets,
+        })
+    }
+
+    /// Counts the paragraphs of a document. Paragraphs that occur several times in the same document are counted once.
+    pub fn add_document(&mut self, text: &str) -> Result<(), anyhow::Error> {
+        let hashes: HashSet<u64> = text.lines().filter_map(paragraph_hash).collect();
+        let num_buckets = self.buckets.len() as u64;
+        for hash in hashes {
+            self.buckets[(hash % num_buckets) as usize].write_all(&hash.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Returns the hashes of all paragraphs that occur in more than `max_occurrences` documents
+    /// and deletes the bucket files.
+    pub fn frequent_paragraphs(
+        self,
+        max_occurrences: usize,
+    ) -> Result<HashSet<u64>, anyhow::Error> {
+        let mut frequent = HashSet::new();
+        for (bucket, path) in self.buckets.into_iter().zip(&self.bucket_paths) {
+            drop(bucket.into_inner()?);
+            let mut bytes = Vec::new();
+            BufReader::new(File::open(path)?)
+                .read_to_end(&mut bytes)
+                .with_context(|| format!("Failed to read {}", path.display()))?;
+            let mut hashes: Vec<u64> = bytes
+                .chunks_exact(8)
+                .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()))
+                .collect();
+            hashes.sort_unstable();
+            frequent.extend(
+                hashes
+                    .chunk_by(|a, b| a == b)
+                    .filter(|run| run.len() > max_occurrences)
+                    .map(|run| run[0]),
+            );
+            std::fs::remove_file(path)?;
+        }
+        Ok(frequent)
+    }
+}
+
+/// Returns the byte ranges of the lines of `text`, without line breaks, whose hash is not in `frequent`.
+fn kept_paragraphs(text: &str, frequent: &HashSet<u64>) -> Vec<Range<usize>> {
+    let mut kept = Vec::new();
+    let mut start = 0;
+    for line in text.split_inclusive('\n') {
+        let paragraph = match line.strip_suffix('\n') {
+            Some(paragraph) => paragraph.strip_suffix('\r').unwrap_or(paragraph),
+            None => line,
+        };
+        if paragraph_hash(paragraph).is_none_or(|hash| !frequent.contains(&hash)) {
+            kept.push(start..start + paragraph.len());
+        }
+        start += line.len();
+    }
+    kept
+}
+
+/// Removes all paragraphs whose hash is in `frequent` from `text`.
+pub fn remove_frequent_paragraphs(text: &str, frequent: &HashSet<u64>) -> String {
+    kept_paragraphs(text, frequent)
+        .into_iter()
+        .map(|range| &text[range])
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Removes all paragraphs whose hash is in `frequent` from the text of `record`.
+/// PII spans move with their paragraphs and are dropped with them. The token count, the perplexities
+/// and the quality tier describe the old text, so they are cleared if the text changes.
+pub fn remove_frequent_paragraphs_from_record(record: &mut OutputRecord, frequent: &HashSet<u64>) {
+    let old_text = &record.document.text;
+    let kept = kept_paragraphs(old_text, frequent);
+    let mut text = String::with_capacity(old_text.len());
+    let mut moved = Vec::with_capacity(kept.len());
+    for (i, range) in kept.into_iter().enumerate() {
+        if i > 0 {
+            text.push('\n');
+        }
+        moved.push((range.clone(), text.len()));
+        text.push_str(&old_text[range]);
+    }
+    if text == *old_text {
+        return;
+    }
+    record.pii_spans.retain_mut(|span| {
+        let Some((range, start)) = moved
+            .iter()
+            .find(|(range, _)| range.start <= span.start && span.end <= range.end)
+        else {
+            return false;
+        };
+        span.start = span.start - range.start + start;
+        span.end = span.end - range.start + start;
+        true
+    });
+    record.document.text = text;
+    record.num_tokens = None;
+    record.perplexity = None;
+    record.paragraph_perplexities.clear();
+    record.quality_tier = None;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::{
+        paragraph_hash, remove_frequent_paragraphs, remove_frequent_paragraphs_from_record,
+        ParagraphCounter,
+    };
+    use crate::{
+        output::OutputRecord,
+        pii::{PiiKind, PiiSpan},
+    };
+
+    #[test]
+    fn removes_frequent_paragraphs() {
+        assert_eq!(
+            paragraph_hash("© 2023 Example Inc. All rights reserved."),
+            paragraph_hash("© 2024 example inc - all rights reserved")
+        );
+        assert_eq!(paragraph_hash(" | "), None);
+
+        let documents = [
+            "Home | News | Contact\nThe first article is about the city library.\n© 2023 Example Inc.",
+            "Home | News | Contact\nThe second article is about the weather.\n© 2024 Example Inc.",
+            "The third article is about football.\nThe third article is about football.\n© 2024 Example Inc.",
+        ];
+        let work_dir =
+            std::env::temp_dir().join(format!("paragraph-dedup-test-{}", std::process::id()));
+        let mut counter = ParagraphCounter::new(&work_dir, 4).unwrap();
+        for document in documents {
+            counter.add_document(document).unwrap();
+        }
+        let frequent = counter.frequent_paragraphs(1).unwrap();
+        assert_eq!(frequent.len(), 2);
+        assert_eq!(
+            remove_frequent_paragraphs(documents[0], &frequent),
+            "The first article is about the city library."
+        );
+        assert_eq!(
+            remove_frequent_paragraphs(documents[2], &frequent),
+            "The third article is about football.\nThe third article is about football."
+        );
+        std::fs::remove_dir(&work_dir).unwrap();
+    }
+
+    #[test]
+    fn moves_pii_spans_with_their_paragraphs() {
+        let mut record: OutputRecord = serde_json::from_str(
+            r#"{"url": "https://example.com/", "timestamp": "20240101000000", "warc_filename": "a.warc.gz",
+                "warc_offset": 0, "warc_length": 0, "text": "Mail <EMAIL>\r\nMenu\nCall <PHONE> or <EMAIL>",
+                "num_tokens": 12, "perplexity": 20.0, "paragraph_perplexities": [10.0, 20.0, 30.0],
+                "quality_tier": "head"}"#,
+        )
+        .unwrap();
+        let span = |kind, start, end| PiiSpan { kind, start, end };
+        record.pii_spans = vec![
+            span(PiiKind::Email, 5, 12),
+            span(PiiKind::Phone, 24, 31),
+            span(PiiKind::Email, 35, 42),
+        ];
+        let frequent: HashSet<u64> = paragraph_hash("Mail <EMAIL>").into_iter().collect();
+        remove_frequent_paragraphs_from_record(&mut record, &frequent);
+        assert_eq!(record.document.text, "Menu\nCall <PHONE> or <EMAIL>");
+        assert_eq!(
+            record.pii_spans,
+            vec![span(PiiKind::Phone, 10, 17), span(PiiKind::Email, 21, 28)]
+        );
+        assert_eq!(&record.document.text[10..17], "<PHONE>");
+        assert_eq!(&record.document.text[21..28], "<EMAIL>");
+        assert_eq!(record.num_tokens, None);
+        assert_eq!(record.perplexity, None);
+        assert!(record.paragraph_perplexities.is_empty());
+        assert_eq!(record.quality_tier, None);
+    }
+}
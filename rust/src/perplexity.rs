@@ -0,0 +1,618 @@
+//! This module scores documents by their perplexity under an n-gram language model, like CCNet does.
+//!
+//! A language model that is trained on a reference corpus such as Wikipedia assigns a low perplexity to text
+//! that resembles the reference corpus and a high perplexity to spam, lists of keywords and broken text.
+//! The models are trained with KenLM on text that was normalized with [normalize_line], e.g. with
+//! `prepare_lm normalize < wiki.txt | lmplz -o 5 > wiki.arpa`, and loaded by the worker with [NgramModel::load].
+//! Large ARPA files are slow to parse, so `prepare_lm binarize` converts them into a binary format that loads faster.
+//! The binary format of KenLM itself is not supported.
+//!
+//! The n-grams of every order are kept in flat arrays sorted by their word ids, which takes `4 * n + 8` bytes per
+//! n-gram of order `n` and `4 * n + 4` bytes per n-gram of the highest order, which has no back-off weights.
+//! A 5-gram model with 500 million n-grams therefore needs about 12 GB of memory.
+//!
+//! Every paragraph, i.e. every line of the text, is scored as one sentence. The perplexity of a document is
+//! computed from the probabilities of all its words, and documents are assigned to a [QualityTier]
+//! by per-language perplexity thresholds.
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    static ref QUALITY_TIERS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "perplexity_quality_tiers",
+        "Number of scored documents per language and perplexity quality tier",
+        &["language", "tier"]
+    )
+    .unwrap();
+}
+
+/// The first bytes of a model that was written with [NgramModel::save_binary].
+const BINARY_MAGIC: &[u8; 8] = b"PLNGRAM1";
+
+/// The first bytes of a binary KenLM model.
+const KENLM_BINARY_MAGIC: &[u8] = b"mmap lm ";
+
+/// The log10 probability of unknown words if the model has no `<unk>` token, the same as in KenLM.
+const UNKNOWN_WORD_LOG_PROB: f32 = -100.0;
+
+/// The key of the model in [PerplexityConfig::models] that scores documents whose language has no model.
+pub const DEFAULT_MODEL: &str = "default";
+
+/// Lowercases the line, replaces all digits with zero and removes punctuation, so that only words
+/// separated by single spaces remain. Documents and the training corpus of a model have to be normalized the same way.
+pub fn normalize_line(line: &str) -> String {
+    line.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            word.chars()
+                .flat_map(char::to_lowercase)
+                .map(|c| if c.is_numeric() { '0' } else { c })
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The n-grams of one order, sorted by their word ids so that they can be found with a binary search.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct Ngrams {
+    order: usize,
+    /// The word ids of all n-grams, `order` ids per n-gram.
+    ids: Vec<u32>,
+    /// The log10 probability of every n-gram.
+    log_probs: Vec<f32>,
+    /// The back-off weight of every n-gram. Empty for the highest order, which has no back-off weights.
+    backoffs: Vec<f32>,
+}
+
+impl Ngrams {
+    fn new(order: usize) -> Self {
+        Self {
+            order,
+            ..Default::default()
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.log_probs.len()
+    }
+
+    fn ids(&self, index: usize) -> &[u32] {
+        &self.ids[index * self.order..(index + 1) * self.order]
+    }
+
+    fn backoff(&self, index: usize) -> f32 {
+        self.backoffs.get(index).copied().unwrap_or(0.0)
+    }
+
+    /// Returns the index of an n-gram.
+    fn find(&self, ngram: &[u32]) -> Option<usize> {
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self.ids(mid).cmp(ngram) {
+                Ordering::Less => low = mid + 1,
+                Ordering::Greater => high = mid,
+                Ordering::Equal => return Some(mid),
+            }
+        }
+        None
+    }
+
+    fn is_sorted(&self) -> bool {
+        (1..self.len()).all(|i| self.ids(i - 1) < self.ids(i))
+    }
+
+    /// Sorts the n-grams by their word ids, which fails if an n-gram occurs twice.
+    fn sort(&mut self) -> Result<(), anyhow::Error> {
+        if self.is_sorted() {
+            return Ok(());
+        }
+        let mut permutation: Vec<usize> = (0..self.len()).collect();
+        permutation.sort_unstable_by(|a, b| self.ids(*a).cmp(self.ids(*b)));
+        if let Some(pair) = permutation
+            .windows(2)
+            .find(|pair| self.ids(pair[0]) == self.ids(pair[1]))
+        {
+            anyhow::bail!(
+                "The {}-gram {:?} occurs twice",
+                self.order,
+                self.ids(pair[0])
+            );
+        }
+        self.ids = permutation
+            .iter()
+            .flat_map(|i| self.ids(*i))
+            .copied()
+            .collect();
+        self.log_probs = permutation.iter().map(|i| self.log_probs[*i]).collect();
+        if !self.backoffs.is_empty() {
+            self.backoffs = permutation.iter().map(|i| self.backoffs[*i]).collect();
+        }
+        Ok(())
+    }
+}
+
+/// A back-off n-gram language model with log10 probabilities, as in the ARPA format.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NgramModel {
+    /// The ids of all words of the model, which are the words of its unigrams.
+    vocab: HashMap<String, u32>,
+    /// The n-grams per order, starting with unigrams.
+    ngrams: Vec<Ngrams>,
+}
+
+impl NgramModel {
+    /// Loads a model from an ARPA file, which may be gzip-compressed, or from a binary model.
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let file = File::open(path)
+            .with_context(|| format!("Failed to open language model {}", path.display()))?;
+        let mut reader: Box<dyn BufRead> = if path.extension().is_some_and(|e| e == "gz") {
+            Box::new(BufReader::new(flate2::read::MultiGzDecoder::new(file)))
+        } else {
+            Box::new(BufReader::new(file))
+        };
+        let header = reader.fill_buf()?;
+        let model = if header.starts_with(BINARY_MAGIC) {
+            Self::read_binary(reader)
+        } else if header.starts_with(KENLM_BINARY_MAGIC) {
+            anyhow::bail!(
+                "KenLM binary models are not supported, convert the ARPA file with `prepare_lm binarize` instead"
+            );
+        } else {
+            Self::parse_arpa(reader)
+        };
+        model.with_context(|| format!("Failed to load language model {}", path.display()))
+    }
+
+    /// Parses a model in the ARPA format.
+    pub fn parse_arpa(reader: impl BufRead) -> Result<Self, anyhow::Error> {
+        let mut model = Self {
+            vocab: HashMap::new(),
+            ngrams: Vec::new(),
+        };
+        // The order of the n-grams of the current section, or `None` outside of n-gram sections.
+        let mut order: Option<usize> = None;
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "\\data\\" || line.starts_with("ngram ") {
+                order = None;
+                continue;
+            }
+            if line == "\\end\\" {
+                break;
+            }
+            if let Some(section) = line.strip_prefix('\\') {
+                let n: usize = section
+                    .strip_suffix("-grams:")
+                    .and_then(|n| n.parse().ok())
+                    .with_context(|| format!("Unknown ARPA section {}", line))?;
+                anyhow::ensure!(
+                    n == model.ngrams.len() + 1,
+                    "ARPA section {} is out of order",
+                    line
+                );
+                model.ngrams.push(Ngrams::new(n));
+                order = Some(n);
+                continue;
+            }
+            let n = order.with_context(|| format!("Unexpected ARPA line {}", line))?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            anyhow::ensure!(
+                fields.len() == n + 1 || fields.len() == n + 2,
+                "Invalid {}-gram {}",
+                n,
+                line
+            );
+            let log_prob: f32 = fields[0].parse()?;
+            let backoff: f32 = fields.get(n + 1).map_or(Ok(0.0), |b| b.parse())?;
+            let ids = fields[1..=n]
+                .iter()
+                .map(|word| {
+                    if n == 1 && !model.vocab.contains_key(*word) {
+                        let id = model.vocab.len() as u32;
+                        model.vocab.insert(word.to_string(), id);
+                    }
+                    model
+                        .vocab
+                        .get(*word)
+                        .copied()
+                        .with_context(|| format!("Word {} of {} is not a unigram", word, line))
+                })
+                .collect::<Result<Vec<u32>, _>>()?;
+            let ngrams = &mut model.ngrams[n - 1];
+            ngrams.ids.extend(ids);
+            ngrams.log_probs.push(log_prob);
+            ngrams.backoffs.push(backoff);
+        }
+        anyhow::ensure!(!model.ngrams.is_empty(), "The model contains no n-grams");
+        if let Some(highest) = model.ngrams.last_mut() {
+            highest.backoffs = Vec::new();
+        }
+        for ngrams in &mut model.ngrams {
+            ngrams.sort()?;
+        }
+        Ok(model)
+    }
+
+    /// Writes the model in a binary format that [NgramModel::load] reads much faster than ARPA.
+    pub fn save_binary(&self, path: &Path) -> Result<(), anyhow::Error> {
+        let file = File::create(path)
+            .with_context(|| format!("Failed to create language model {}", path.display()))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(BINARY_MAGIC)?;
+        writer.write_all(&(self.ngrams.len() as u32).to_le_bytes())?;
+        let mut words: Vec<(&String, &u32)> = self.vocab.iter().collect();
+        words.sort_by_key(|(_, id)| **id);
+        writer.write_all(&(words.len() as u32).to_le_bytes())?;
+        for (word, _) in words {
+            writer.write_all(&(word.len() as u32).to_le_bytes())?;
+            writer.write_all(word.as_bytes())?;
+        }
+        // The n-grams are written in their sorted order, so that they are loaded without sorting them again.
+        for ngrams in &self.ngrams {
+            writer.write_all(&(ngrams.len() as u64).to_le_bytes())?;
+            for id in &ngrams.ids {
+                writer.write_all(&id.to_le_bytes())?;
+            }
+            for value in ngrams.log_probs.iter().chain(&ngrams.backoffs) {
+                writer.write_all(&value.to_le_bytes())?;
+            }
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn read_binary(mut reader: impl Read) -> Result<Self, anyhow::Error> {
+        let mut bytes = [0u8; 8];
+        reader.read_exact(&mut bytes)?;
+        fn read_u32(reader: &mut dyn Read) -> Result<u32, anyhow::Error> {
+            let mut bytes = [0u8; 4];
+            reader.read_exact(&mut bytes)?;
+            Ok(u32::from_le_bytes(bytes))
+        }
+        let order = read_u32(&mut reader)? as usize;
+        anyhow::ensure!(order > 0, "The model contains no n-grams");
+        let vocab_size = read_u32(&mut reader)?;
+        let mut vocab = HashMap::with_capacity(vocab_size as usize);
+        for id in 0..vocab_size {
+            let mut word = vec![0u8; read_u32(&mut reader)? as usize];
+            reader.read_exact(&mut word)?;
+            vocab.insert(String::from_utf8(word)?, id);
+        }
+        let mut ngrams = Vec::with_capacity(order);
+        for n in 1..=order {
+            let mut count = [0u8; 8];
+            reader.read_exact(&mut count)?;
+            let count = u64::from_le_bytes(count) as usize;
+            let to_f32 = |values: Vec<u32>| values.into_iter().map(f32::from_bits).collect();
+            let order_ngrams = Ngrams {
+                order: n,
+                ids: read_u32s(&mut reader, count * n)?,
+                log_probs: to_f32(read_u32s(&mut reader, count)?),
+                backoffs: to_f32(read_u32s(&mut reader, if n < order { count } else { 0 })?),
+            };
+            anyhow::ensure!(order_ngrams.is_sorted(), "The {}-grams are not sorted", n);
+            ngrams.push(order_ngrams);
+        }
+        Ok(Self { vocab, ngrams })
+    }
+
+    /// The highest order of the n-grams of the model.
+    pub fn order(&self) -> usize {
+        self.ngrams.len()
+    }
+
+    /// Returns the log10 probability of the last word of `ngram` given the words before it,
+    /// backing off to shorter contexts if the n-gram is not in the model.
+    fn log_prob(&self, mut ngram: &[u32]) -> f32 {
+        let mut backoff = 0.0;
+        loop {
+            let ngrams = &self.ngrams[ngram.len() - 1];
+            if let Some(index) = ngrams.find(ngram) {
+                return backoff + ngrams.log_probs[index];
+            }
+            if ngram.len() == 1 {
+                return backoff + UNKNOWN_WORD_LOG_PROB;
+            }
+            let context = &ngram[..ngram.len() - 1];
+            let contexts = &self.ngrams[context.len() - 1];
+            backoff += contexts
+                .find(context)
+                .map_or(0.0, |index| contexts.backoff(index));
+            ngram = &ngram[1..];
+        }
+    }
+
+    /// Returns the sum of the log10 probabilities of the words of a normalized sentence and of the end of the sentence,
+    /// and the number of scored tokens.
+    pub fn score_sentence(&self, sentence: &str) -> (f64, usize) {
+        let unknown = self.vocab.get("<unk>").copied().unwrap_or(u32::MAX);
+        let id = |word: &str| self.vocab.get(word).copied().unwrap_or(unknown);
+        let start = self.vocab.get("<s>").into_iter().copied();
+        let ids: Vec<u32> = start
+            .clone()
+            .chain(sentence.split(' ').filter(|w| !w.is_empty()).map(id))
+            .chain(std::iter::once(id("</s>")))
+            .collect();
+        let first = start.count();
+        let log_prob = (first..ids.len())
+            .map(|i| {
+                let ngram = &ids[(i + 1).saturating_sub(self.order())..=i];
+                self.log_prob(ngram) as f64
+            })
+            .sum();
+        (log_prob, ids.len() - first)
+    }
+}
+
+/// Reads `len` little-endian `u32` values in blocks, which is much faster than reading them one by one.
+fn read_u32s(reader: &mut dyn Read, len: usize) -> Result<Vec<u32>, anyhow::Error> {
+    let mut values = Vec::with_capacity(len);
+    let mut buffer = vec![0u8; 1 << 16];
+    while values.len() < len {
+        let block = &mut buffer[..(len - values.len()).min(1 << 14) * 4];
+        reader.read_exact(block)?;
+        values.extend(
+            block
+                .chunks_exact(4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap())),
+        );
+    }
+    Ok(values)
+}
+
+/// Converts a sum of log10 probabilities of `num_tokens` tokens into a perplexity, rounded to one decimal like in CCNet.
+fn perplexity(log_prob: f64, num_tokens: usize) -> f64 {
+    (10f64.powf(-log_prob / num_tokens as f64) * 10.0).round() / 10.0
+}
+
+/// A quality tier of documents, by the perplexity of their text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QualityTier {
+    Head,
+    Middle,
+    Tail,
+}
+
+impl QualityTier {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            QualityTier::Head => "head",
+            QualityTier::Middle => "middle",
+            QualityTier::Tail => "tail",
+        }
+    }
+}
+
+/// The model of a language and the perplexity thresholds of its quality tiers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PerplexityModelConfig {
+    /// Path to the ARPA file or binary model.
+    pub path: PathBuf,
+    /// Documents with at most this perplexity are in the head tier.
+    /// Tiers are only assigned if both thresholds are set.
+    pub head_max_perplexity: Option<f64>,
+    /// Documents with at most this perplexity that are not in the head tier are in the middle tier,
+    /// all others are in the tail tier.
+    pub middle_max_perplexity: Option<f64>,
+}
+
+/// Configuration of the perplexity scoring stage.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PerplexityConfig {
+    pub enabled: bool,
+    /// The models per language, e.g. `eng`. The model with the key `default` scores documents
+    /// whose language is unknown or has no model, all other documents are not scored.
+    pub models: BTreeMap<String, PerplexityModelConfig>,
+    /// Whether the perplexity of every paragraph is written to the `paragraph_perplexities` field of the output.
+    pub record_paragraphs: bool,
+}
+
+/// The perplexity of a document and its quality tier.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PerplexityScore {
+    pub perplexity: f64,
+    /// The perplexities of all paragraphs that contain words, in order. Only set if configured.
+    pub paragraph_perplexities: Vec<f64>,
+    pub tier: Option<QualityTier>,
+}
+
+struct LanguageScorer {
+    model: NgramModel,
+    tier_thresholds: Option<(f64, f64)>,
+}
+
+/// Scores documents with the language models of a [PerplexityConfig].
+pub struct PerplexityScorer {
+    models: BTreeMap<String, LanguageScorer>,
+    record_paragraphs: bool,
+}
+
+impl PerplexityScorer {
+    /// Loads the models of the config. Returns `Ok(None)` if scoring is disabled.
+    pub fn from_config(config: &PerplexityConfig) -> Result<Option<Self>, anyhow::Error> {
+        if !config.enabled {
+            return Ok(None);
+        }
+        let mut models = BTreeMap::new();
+        for (language, model_config) in &config.models {
+            let tier_thresholds = match (
+                model_config.head_max_perplexity,
+                model_config.middle_max_perplexity,
+            ) {
+                (Some(head), Some(middle)) if head <= middle => Some((head, middle)),
+                (None, None) => None,
+                _ => anyhow::bail!(
+                    "Set both head_max_perplexity and middle_max_perplexity of language {}, with head <= middle",
+                    language
+                ),
+            };
+            let model = NgramModel::load(&model_config.path)?;
+            tracing::info!(
+                "Loaded {}-gram model with {} words for language {}",
+                model.order(),
+                model.vocab.len(),
+                language
+            );
+            models.insert(
+                language.clone(),
+                LanguageScorer {
+                    model,
+                    tier_thresholds,
+                },
+            );
+        }
+        Ok(Some(Self {
+            models,
+            record_paragraphs: config.record_paragraphs,
+        }))
+    }
+
+    /// Scores the text with the model of `language`, or the default model if there is none.
+    /// Returns `None` if there is no model for the language or the text contains no words.
+    /// Quality tiers are counted in the `perplexity_quality_tiers` metric.
+    pub fn score(&self, text: &str, language: Option<&str>) -> Option<PerplexityScore> {
+        let (language, scorer) = language
+            .and_then(|language| self.models.get_key_value(language))
+            .or_else(|| self.models.get_key_value(DEFAULT_MODEL))?;
+        let mut total_log_prob = 0.0;
+        let mut total_tokens = 0;
+        let mut paragraph_perplexities = Vec::new();
+        for line in text.lines() {
+            let normalized = normalize_line(line);
+            if normalized.is_empty() {
+                continue;
+            }
+            let (log_prob, num_tokens) = scorer.model.score_sentence(&normalized);
+            total_log_prob += log_prob;
+            total_tokens += num_tokens;
+            if self.record_paragraphs {
+                paragraph_perplexities.push(perplexity(log_prob, num_tokens));
+            }
+        }
+        if total_tokens == 0 {
+            return None;
+        }
+        let perplexity = perplexity(total_log_prob, total_tokens);
+        let tier = scorer.tier_thresholds.map(|(head, middle)| {
+            if perplexity <= head {
+                QualityTier::Head
+            } else if perplexity <= middle {
+                QualityTier::Middle
+            } else {
+                QualityTier::Tail
+            }
+        });
+        if let Some(tier) = tier {
+            QUALITY_TIERS_COUNTER
+                .with_label_values(&[language.as_str(), tier.as_str()])
+                .inc();
+        }
+        Some(PerplexityScore {
+            perplexity,
+            paragraph_perplexities,
+            tier,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{
+        normalize_line, NgramModel, PerplexityConfig, PerplexityModelConfig, PerplexityScorer,
+        QualityTier, BINARY_MAGIC,
+    };
+
+    const ARPA: &str = "\\data\\
+ngram 1=6
+ngram 2=4
+
+\\1-grams:
+-1.0\t<unk>\t0
+-99\t<s>\t-0.5
+-1.0\t</s>\t0
+-0.5\tthe\t-0.3
+-0.8\tcat\t-0.2
+-0.9\tsat\t-0.1
+
+\\2-grams:
+-0.1\tthe cat
+-0.2\t<s> the
+-0.3\tcat sat
+-0.2\tsat </s>
+
+\\end\\
+";
+
+    #[test]
+    fn scores_documents() {
+        assert_eq!(
+            normalize_line("The cat, born in 2019!"),
+            "the cat born in 0000"
+        );
+
+        let model = NgramModel::parse_arpa(ARPA.as_bytes()).unwrap();
+        assert_eq!(model.order(), 2);
+        // <s> the (-0.2), the cat (-0.1), cat sat (-0.3), sat </s> (-0.2).
+        let (log_prob, num_tokens) = model.score_sentence("the cat sat");
+        assert!((log_prob + 0.8).abs() < 1e-6);
+        assert_eq!(num_tokens, 4);
+        // cat dog: back-off of cat (-0.2) + <unk> (-1.0), then back-off of <unk> (0) + </s> (-1.0).
+        let (log_prob, _) = model.score_sentence("the cat dog");
+        assert!((log_prob + 2.5).abs() < 1e-6);
+        let duplicate = ARPA.replace("-0.3\tcat sat\n", "-0.3\tcat sat\n-0.4\tcat sat\n");
+        assert!(NgramModel::parse_arpa(duplicate.as_bytes()).is_err());
+
+        let dir = std::env::temp_dir().join(format!("perplexity-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("model.bin");
+        model.save_binary(&path).unwrap();
+        assert_eq!(NgramModel::load(&path).unwrap(), model);
+        std::fs::write(&path, [&BINARY_MAGIC[..], &[0; 8]].concat()).unwrap();
+        assert!(NgramModel::load(&path).is_err());
+        std::fs::write(dir.join("model.arpa"), ARPA).unwrap();
+
+        let scorer = PerplexityScorer::from_config(&PerplexityConfig {
+            enabled: true,
+            models: BTreeMap::from([(
+                "eng".to_string(),
+                PerplexityModelConfig {
+                    path: dir.join("model.arpa"),
+                    head_max_perplexity: Some(2.0),
+                    middle_max_perplexity: Some(10.0),
+                },
+            )]),
+            record_paragraphs: true,
+        })
+        .unwrap()
+        .unwrap();
+        let score = scorer
+            .score("The cat sat.\n\nThe cat dog", Some("eng"))
+            .unwrap();
+        assert_eq!(score.paragraph_perplexities, [1.6, 4.2]);
+        assert_eq!(score.perplexity, 2.6);
+        assert_eq!(score.tier, Some(QualityTier::Middle));
+        assert!(scorer.score("The cat sat.", Some("deu")).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
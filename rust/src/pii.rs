@@ -0,0 +1,267 @@
+//! This module redacts personally identifiable information (PII) from the extracted text.
+//!
+//! Candidates are found with regular expressions and then validated, e.g. IP addresses must parse and
+//! credit card numbers must pass the Luhn check, which keeps false positives such as version numbers
+//! or order numbers low. Every match is replaced with a placeholder token per [PiiKind].
+//! The worker redacts documents after the quality filters, so that the filters see the original text.
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter_vec, IntCounterVec};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+lazy_static! {
+    static ref REDACTIONS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "pii_redactions",
+        "Number of redacted matches of personal data, per type",
+        &["kind"]
+    )
+    .unwrap();
+    static ref EMAIL_REGEX: Regex =
+        Regex::new(r"[A-Za-z0-9._%+-]+@[A-Za-z0-9-]+(?:\.[A-Za-z0-9-]+)*\.[A-Za-z]{2,}").unwrap();
+    static ref IPV4_REGEX: Regex = Regex::new(r"\d{1,3}(?:\.\d{1,3}){3}").unwrap();
+    static ref IPV6_REGEX: Regex =
+        Regex::new(r"[0-9A-Fa-f]{0,4}(?::[0-9A-Fa-f]{0,4}){2,7}").unwrap();
+    static ref CREDIT_CARD_REGEX: Regex = Regex::new(r"\d(?:[ -]?\d){12,18}").unwrap();
+    static ref PHONE_REGEX: Regex =
+        Regex::new(r"(?:\+\d{1,3}[ .-]?)?(?:\(\d{1,5}\)[ .-]?)?\d{2,8}(?:[ .-]\d{2,8}){1,4}")
+            .unwrap();
+}
+
+/// A type of personal data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PiiKind {
+    Email,
+    /// IPv4 and IPv6 addresses.
+    IpAddress,
+    /// Numbers with 13 to 19 digits that pass the Luhn check.
+    CreditCard,
+    /// Phone numbers with 10 to 15 digits and separators, or with at least 8 digits and a country code.
+    Phone,
+}
+
+impl PiiKind {
+    /// All kinds, in the order in which overlapping matches take precedence.
+    pub const ALL: [PiiKind; 4] = [
+        PiiKind::Email,
+        PiiKind::IpAddress,
+        PiiKind::CreditCard,
+        PiiKind::Phone,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PiiKind::Email => "email",
+            PiiKind::IpAddress => "ip_address",
+            PiiKind::CreditCard => "credit_card",
+            PiiKind::Phone => "phone",
+        }
+    }
+
+    /// Returns the byte ranges of all valid matches in `text`.
+    fn find(&self, text: &str) -> Vec<(usize, usize)> {
+        let candidates = |regex: &Regex, is_valid: &dyn Fn(&str) -> bool| {
+            regex
+                .find_iter(text)
+                .filter(|m| is_valid(m.as_str()))
+                .map(|m| (m.start(), m.end()))
+                .collect::<Vec<_>>()
+        };
+        let matches = match self {
+            PiiKind::Email => candidates(&EMAIL_REGEX, &|email| {
+                !email.starts_with('.') && !email.contains(".@") && !email.contains("..")
+            }),
+            PiiKind::IpAddress => {
+                let mut matches = candidates(&IPV4_REGEX, &|ip| Ipv4Addr::from_str(ip).is_ok());
+                matches.extend(candidates(&IPV6_REGEX, &|ip| {
+                    ip.split(':').filter(|group| !group.is_empty()).count() >= 2
+                        && Ipv6Addr::from_str(ip).is_ok()
+                }));
+                matches
+            }
+            PiiKind::CreditCard => candidates(&CREDIT_CARD_REGEX, &|number| {
+                let digits: Vec<u32> = number.chars().filter_map(|c| c.to_digit(10)).collect();
+                (13..=19).contains(&digits.len()) && passes_luhn_check(&digits)
+            }),
+            PiiKind::Phone => candidates(&PHONE_REGEX, &|number| {
+                let digits = number.chars().filter(char::is_ascii_digit).count();
+                let min_digits = if number.starts_with('+') { 8 } else { 10 };
+                (min_digits..=15).contains(&digits)
+            }),
+        };
+        matches
+            .into_iter()
+            .filter(|&(start, end)| is_standalone(text, start, end))
+            .collect()
+    }
+}
+
+/// Whether the Luhn checksum of the digits is valid.
+fn passes_luhn_check(digits: &[u32]) -> bool {
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &digit)| match (i % 2 == 1, digit * 2) {
+            (true, doubled) if doubled > 9 => doubled - 9,
+            (true, doubled) => doubled,
+            (false, _) => digit,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+/// Whether a match is not part of a longer word or number, e.g. a phone number in a longer sequence of digits.
+fn is_standalone(text: &str, start: usize, end: usize) -> bool {
+    let before = text[..start].chars().next_back();
+    let mut after = text[end..].chars();
+    let (next, next_but_one) = (after.next(), after.next());
+    let continues_number = |c: Option<char>| c.is_some_and(|c| c.is_ascii_digit());
+    before.is_none_or(|c| !c.is_alphanumeric() && !"@.:+-".contains(c))
+        && next.is_none_or(|c| {
+            !c.is_alphanumeric()
+                && c != '@'
+                && !(".,:-".contains(c) && continues_number(next_but_one))
+        })
+}
+
+/// The tokens with which matches are replaced.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PiiPlaceholders {
+    pub email: String,
+    pub ip_address: String,
+    pub credit_card: String,
+    pub phone: String,
+}
+
+impl Default for PiiPlaceholders {
+    fn default() -> Self {
+        Self {
+            email: "<EMAIL>".to_string(),
+            ip_address: "<IP_ADDRESS>".to_string(),
+            credit_card: "<CREDIT_CARD>".to_string(),
+            phone: "<PHONE>".to_string(),
+        }
+    }
+}
+
+impl PiiPlaceholders {
+    pub fn get(&self, kind: PiiKind) -> &str {
+        match kind {
+            PiiKind::Email => &self.email,
+            PiiKind::IpAddress => &self.ip_address,
+            PiiKind::CreditCard => &self.credit_card,
+            PiiKind::Phone => &self.phone,
+        }
+    }
+}
+
+/// The location of a placeholder in the redacted text, as byte offsets.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PiiSpan {
+    pub kind: PiiKind,
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Configuration of the PII redaction stage.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PiiConfig {
+    pub enabled: bool,
+    /// The kinds of personal data that are redacted.
+    pub kinds: Vec<PiiKind>,
+    pub placeholders: PiiPlaceholders,
+    /// Whether the locations of the placeholders are written to the `pii_spans` field of the output.
+    pub record_spans: bool,
+}
+
+impl Default for PiiConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            kinds: PiiKind::ALL.to_vec(),
+            placeholders: PiiPlaceholders::default(),
+            record_spans: false,
+        }
+    }
+}
+
+impl PiiConfig {
+    /// Replaces all personal data of the configured kinds in `text` with placeholders and returns the
+    /// redacted text together with the spans of the placeholders. If matches of different kinds overlap,
+    /// the kind that comes first in [PiiKind::ALL] wins. Matches are counted in the `pii_redactions` metric.
+    pub fn redact(&self, text: &str) -> (String, Vec<PiiSpan>) {
+        let mut matches: Vec<(usize, usize, PiiKind)> = Vec::new();
+        for kind in PiiKind::ALL {
+            if !self.kinds.contains(&kind) {
+                continue;
+            }
+            for (start, end) in kind.find(text) {
+                if matches.iter().all(|&(s, e, _)| end <= s || start >= e) {
+                    matches.push((start, end, kind));
+                }
+            }
+        }
+        matches.sort_by_key(|&(start, _, _)| start);
+
+        let mut redacted = String::with_capacity(text.len());
+        let mut spans = Vec::with_capacity(matches.len());
+        let mut last_end = 0;
+        for (start, end, kind) in matches {
+            REDACTIONS_COUNTER.with_label_values(&[kind.as_str()]).inc();
+            redacted.push_str(&text[last_end..start]);
+            let span_start = redacted.len();
+            redacted.push_str(self.placeholders.get(kind));
+            spans.push(PiiSpan {
+                kind,
+                start: span_start,
+                end: redacted.len(),
+            });
+            last_end = end;
+        }
+        redacted.push_str(&text[last_end..]);
+        (redacted, spans)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PiiConfig, PiiKind};
+
+    #[test]
+    fn redacts_personal_data() {
+        let config = PiiConfig::default();
+        let (text, spans) = config.redact(
+            "Contact jane.doe@example.com or call +49 30 1234567 or (555) 123-4567.\n\
+             The server 192.168.0.1 and 2001:db8::8a2e:370:7334 accepted card 4111 1111 1111 1111.",
+        );
+        assert_eq!(
+            text,
+            "Contact <EMAIL> or call <PHONE> or <PHONE>.\n\
+             The server <IP_ADDRESS> and <IP_ADDRESS> accepted card <CREDIT_CARD>."
+        );
+        let kinds: Vec<PiiKind> = spans.iter().map(|span| span.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                PiiKind::Email,
+                PiiKind::Phone,
+                PiiKind::Phone,
+                PiiKind::IpAddress,
+                PiiKind::IpAddress,
+                PiiKind::CreditCard
+            ]
+        );
+        assert_eq!(&text[spans[0].start..spans[0].end], "<EMAIL>");
+
+        let unchanged = "Version 1.2.3 was released on 2024-07-22 at 12:30:45. Order 4111 1111 1111 1112 shipped, see page 1234567.";
+        assert_eq!(config.redact(unchanged).0, unchanged);
+    }
+}
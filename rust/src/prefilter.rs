@@ -0,0 +1,231 @@
+//! This module contains cheap checks that decide whether a record is worth extracting at all.
+//!
+//! The cdx index only tells us what a URL claimed to be at crawl time, so PDFs, images and
+//! very large documents regularly end up in a batch. Extracting them wastes CPU time and rarely yields text.
+//! The checks in this module run before the extractor and look at the size of the payload,
+//! its `Content-Type` and `WARC-Identified-Payload-Type`, and its first bytes.
+use serde::{Deserialize, Serialize};
+
+use crate::http::{mime_essence, HttpResponse};
+
+/// Number of bytes at the beginning of the payload that are sniffed for HTML markup.
+const SNIFF_BYTES: usize = 1024;
+
+/// Tags of which at least one has to appear in the sniffed bytes of an HTML document.
+/// This is a superset of the patterns of the WHATWG MIME sniffing standard.
+const HTML_PATTERNS: &[&[u8]] = &[
+    b"<!doctype html",
+    b"<html",
+    b"<head",
+    b"<body",
+    b"<title",
+    b"<meta",
+    b"<script",
+    b"<style",
+    b"<link",
+    b"<div",
+    b"<table",
+    b"<iframe",
+    b"<h1",
+    b"<p",
+    b"<a ",
+    b"<br",
+    b"<!--",
+];
+
+/// Configuration of the checks that run before text extraction.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrefilterConfig {
+    /// Records whose payload is larger than this are not extracted.
+    pub max_payload_bytes: usize,
+    /// MIME types that may be extracted. Both the `Content-Type` header and the
+    /// `WARC-Identified-Payload-Type` of a record have to be in this list, if present.
+    /// An empty list allows all types.
+    pub allowed_mime_types: Vec<String>,
+    /// Whether the beginning of the payload has to look like HTML.
+    pub sniff_html: bool,
+}
+
+impl Default for PrefilterConfig {
+    fn default() -> Self {
+        Self {
+            max_payload_bytes: 5 * 1024 * 1024,
+            allowed_mime_types: vec!["text/html".to_string(), "application/xhtml+xml".to_string()],
+            sniff_html: true,
+        }
+    }
+}
+
+/// The reasons for which a record is not extracted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    TooLarge,
+    ContentType,
+    IdentifiedPayloadType,
+    NotHtml,
+}
+
+impl Rejection {
+    /// The name of the reason, used as a metrics label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::TooLarge => "too_large",
+            Rejection::ContentType => "content_type",
+            Rejection::IdentifiedPayloadType => "identified_payload_type",
+            Rejection::NotHtml => "not_html",
+        }
+    }
+}
+
+impl PrefilterConfig {
+    /// Checks the length of a WARC record from the cdx index before it is downloaded.
+    /// The record is gzip-compressed, so its payload is at least this large in practice.
+    pub fn check_record_length(&self, length: usize) -> Result<(), Rejection> {
+        if length > self.max_payload_bytes {
+            return Err(Rejection::TooLarge);
+        }
+        Ok(())
+    }
+
+    /// Checks a parsed HTTP response and the `WARC-Identified-Payload-Type` of its record.
+    pub fn check_response(
+        &self,
+        response: &HttpResponse,
+        identified_payload_type: Option<&str>,
+    ) -> Result<(), Rejection> {
+        if response.payload.len() > self.max_payload_bytes {
+            return Err(Rejection::TooLarge);
+        }
+        if !self.is_allowed_mime_type(response.content_type()) {
+            return Err(Rejection::ContentType);
+        }
+        // The identified type of a payload that we decompressed is one of the compressed types, e.g. `application/gzip`.
+        if !response.decompressed && !self.is_allowed_mime_type(identified_payload_type) {
+            return Err(Rejection::IdentifiedPayloadType);
+        }
+        if self.sniff_html && !looks_like_html(&response.payload) {
+            return Err(Rejection::NotHtml);
+        }
+        Ok(())
+    }
+
+    /// Missing types are allowed, the HTML sniffing catches those records that are not HTML.
+    fn is_allowed_mime_type(&self, mime_type: Option<&str>) -> bool {
+        let Some(mime_type) = mime_type else {
+            return true;
+        };
+        let mime_type = mime_essence(mime_type);
+        self.allowed_mime_types.is_empty()
+            || self
+                .allowed_mime_types
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(&mime_type))
+    }
+}
+
+/// Whether the first bytes of the payload contain HTML markup and no NUL bytes, which only occur in binary data.
+/// Payloads with a UTF-16 byte order mark are accepted without further checks.
+pub fn looks_like_html(payload: &[u8]) -> bool {
+    if payload.starts_with(&[0xFF, 0xFE]) || payload.starts_with(&[0xFE, 0xFF]) {
+        return true;
+    }
+    let prefix = &payload[..payload.len().min(SNIFF_BYTES)];
+    if prefix.contains(&0) {
+        return false;
+    }
+    let prefix = prefix.to_ascii_lowercase();
+    HTML_PATTERNS.iter().any(|pattern| {
+        prefix
+            .windows(pattern.len())
+            .any(|window| window == *pattern)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::{parse_http_response, HttpResponse};
+
+    use super::{looks_like_html, PrefilterConfig, Rejection};
+
+    fn response(content_type: &str, payload: &[u8]) -> HttpResponse {
+        HttpResponse {
+            version: 1,
+            status: 200,
+            reason: "OK".to_string(),
+            headers: vec![("Content-Type".to_string(), content_type.to_string())],
+            payload: payload.to_vec(),
+            decompressed: false,
+            truncated: false,
+        }
+    }
+
+    #[test]
+    fn rejects_records_per_reason() {
+        let config = PrefilterConfig {
+            max_payload_bytes: 100,
+            ..Default::default()
+        };
+        let html = b"\n<!DOCTYPE html><html><body>Hi</body></html>";
+        assert_eq!(
+            config.check_response(
+                &response("text/html; charset=utf-8", html),
+                Some("text/html")
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            config.check_response(&response("text/html", &[b' '; 101]), None),
+            Err(Rejection::TooLarge)
+        );
+        assert_eq!(
+            config.check_response(&response("application/pdf", html), None),
+            Err(Rejection::ContentType)
+        );
+        assert_eq!(
+            config.check_response(&response("text/html", html), Some("image/png")),
+            Err(Rejection::IdentifiedPayloadType)
+        );
+        assert_eq!(
+            config.check_response(&response("text/html", b"%PDF-1.4\n%\x00\x01"), None),
+            Err(Rejection::NotHtml)
+        );
+        assert_eq!(config.check_record_length(101), Err(Rejection::TooLarge));
+    }
+
+    #[test]
+    fn accepts_decompressed_payloads() {
+        use std::io::Write;
+
+        let html = b"<!DOCTYPE html><html><body>Hi</body></html>";
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(html).unwrap();
+        let mut body =
+            b"HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Encoding: gzip\r\n\r\n"
+                .to_vec();
+        body.extend(encoder.finish().unwrap());
+
+        let config = PrefilterConfig::default();
+        let decoded =
+            parse_http_response(&body, Some("application/gzip"), config.max_payload_bytes).unwrap();
+        assert!(decoded.decompressed);
+        assert_eq!(decoded.payload, html);
+        assert_eq!(
+            config.check_response(&decoded, Some("application/gzip")),
+            Ok(())
+        );
+        // A payload that is still compressed is rejected.
+        assert_eq!(
+            config.check_response(&response("text/html", html), Some("application/gzip")),
+            Err(Rejection::IdentifiedPayloadType)
+        );
+    }
+
+    #[test]
+    fn sniffs_html() {
+        assert!(looks_like_html(b"  <HTML lang=\"en\"><p>Text</p>"));
+        assert!(looks_like_html(b"<?xml version=\"1.0\"?><html>"));
+        assert!(!looks_like_html(b"{\"json\": true}"));
+        assert!(!looks_like_html(b"\x89PNG\r\n\x1a\n\x00\x00"));
+    }
+}
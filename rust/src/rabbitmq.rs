@@ -12,6 +12,8 @@ use crate::commoncrawl::CdxEntry;
 
 pub const BATCH_SIZE: usize = 1000;
 pub const CC_QUEUE_NAME: &str = "batches";
+/// The prefetch count used by publishers that do not consume any messages.
+pub const DEFAULT_PREFETCH_COUNT: u16 = 1;
 const RABBIT_MQ_TIMEOUT: Duration = Duration::from_secs(20);
 
 /// Tries to get the environment variable `RABBITMQ_CONNECTION_STRING` and panics if not found.
@@ -39,8 +41,9 @@ pub async fn rabbitmq_connection() -> Result<Connection, anyhow::Error> {
 pub async fn rabbitmq_channel_with_queue(
     conn: &Connection,
     queue_name: &str,
+    prefetch_count: u16,
 ) -> Result<(Channel, Queue), anyhow::Error> {
-    let channel = rabbitmq_channel(conn).await?;
+    let channel = rabbitmq_channel(conn, prefetch_count).await?;
     let queue = rabbitmq_declare_queue(&channel, queue_name, FieldTable::default()).await?;
     Ok((channel, queue))
 }
@@ -63,9 +66,13 @@ pub async fn rabbitmq_declare_queue(
     Ok(queue)
 }
 
-/// Creates a RabbitMQ channel and sets the prefetch count to 1.
+/// Creates a RabbitMQ channel and sets the prefetch count, i.e. the number of
+/// unacknowledged messages that a consumer on this channel may hold at once.
 /// Can raise timeout errors if connections time out.
-pub async fn rabbitmq_channel(conn: &Connection) -> Result<Channel, anyhow::Error> {
+pub async fn rabbitmq_channel(
+    conn: &Connection,
+    prefetch_count: u16,
+) -> Result<Channel, anyhow::Error> {
     let channel = tokio::time::timeout(RABBIT_MQ_TIMEOUT, conn.create_channel())
         .await
         .context("Timed out while trying to create a RabbitMQ channel")?
@@ -73,7 +80,7 @@ pub async fn rabbitmq_channel(conn: &Connection) -> Result<Channel, anyhow::Erro
 
     tokio::time::timeout(
         RABBIT_MQ_TIMEOUT,
-        channel.basic_qos(1, BasicQosOptions::default()),
+        channel.basic_qos(prefetch_count, BasicQosOptions::default()),
     )
     .await
     .context("Timed out while trying to set QoS on the channel")?
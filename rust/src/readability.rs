@@ -0,0 +1,337 @@
+//! This module contains a native Rust text extractor that removes boilerplate from HTML documents.
+//!
+//! It follows the main idea of Mozilla's Readability: paragraphs with a lot of text and commas are
+//! likely part of the main content, so they add a score to their parent and grandparent elements.
+//! Class names and IDs such as `article` or `sidebar` adjust these scores, and the element with the
+//! highest score, penalized by its link density, is chosen as the main content.
+//! The text of that element is returned with one line per block-level element.
+//! Metadata such as the title or the canonical URL is read from the `<head>` of the document.
+use std::collections::HashMap;
+
+use scraper::{ElementRef, Html, Node, Selector};
+
+use crate::extractor::ExtractedDocument;
+
+/// Elements whose content is never part of the main text.
+const SKIPPED_TAGS: &[&str] = &[
+    "script", "style", "noscript", "nav", "header", "footer", "aside", "form", "iframe", "svg",
+    "button", "select", "template", "head", "menu", "object", "embed", "canvas",
+];
+
+/// Elements that start a new line in the extracted text.
+const BLOCK_TAGS: &[&str] = &[
+    "address",
+    "article",
+    "blockquote",
+    "br",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "li",
+    "main",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "td",
+    "th",
+    "tr",
+    "ul",
+];
+
+/// Elements whose text contributes to the score of their ancestors.
+const SCORED_TAGS: &[&str] = &["p", "pre", "td", "blockquote"];
+
+const POSITIVE_HINTS: &[&str] = &[
+    "article", "body", "content", "entry", "main", "page", "post", "text", "blog", "story",
+];
+
+const NEGATIVE_HINTS: &[&str] = &[
+    "comment",
+    "sidebar",
+    "footer",
+    "nav",
+    "menu",
+    "share",
+    "social",
+    "banner",
+    "cookie",
+    "advert",
+    "sponsor",
+    "related",
+    "popup",
+    "promo",
+    "widget",
+    "breadcrumb",
+    "masthead",
+];
+
+/// Paragraphs with less text than this are ignored for scoring.
+const MIN_PARAGRAPH_LENGTH: usize = 25;
+
+/// Extracts the main text from an HTML document.
+/// Returns `None` if no element with enough text was found.
+pub fn extract(html: &str) -> Option<String> {
+    extract_with_metadata(html).map(|document| document.text)
+}
+
+/// Extracts the main text and the metadata from an HTML document.
+/// Returns `None` if no element with enough text was found.
+pub fn extract_with_metadata(html: &str) -> Option<ExtractedDocument> {
+    let document = Html::parse_document(html);
+    let text = extract_text(&document)?;
+    Some(ExtractedDocument {
+        text,
+        title: meta_content(&document, &["og:title"])
+            .or_else(|| select_text(&document, "head title")),
+        author: meta_content(&document, &["author", "article:author"]),
+        date: meta_content(
+            &document,
+            &["article:published_time", "date", "dc.date", "pubdate"],
+        ),
+        sitename: meta_content(&document, &["og:site_name", "application-name"]),
+        description: meta_content(&document, &["description", "og:description"]),
+        categories: meta_contents(&document, "article:section"),
+        tags: meta_contents(&document, "article:tag")
+            .into_iter()
+            .chain(
+                meta_content(&document, &["keywords"])
+                    .iter()
+                    .flat_map(|keywords| keywords.split(','))
+                    .map(|keyword| keyword.trim().to_string())
+                    .filter(|keyword| !keyword.is_empty()),
+            )
+            .collect(),
+        canonical_url: select_attr(&document, r#"link[rel="canonical"]"#, "href")
+            .or_else(|| meta_content(&document, &["og:url"])),
+    })
+}
+
+/// Returns the content of the first `<meta>` tag whose `name` or `property` matches one of the given names.
+fn meta_content(document: &Html, names: &[&str]) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| meta_contents(document, name).into_iter().next())
+}
+
+/// Returns the contents of all `<meta>` tags whose `name` or `property` matches the given name.
+fn meta_contents(document: &Html, name: &str) -> Vec<String> {
+    let selector = Selector::parse("meta[content]").expect("Selector is valid");
+    document
+        .select(&selector)
+        .filter(|meta| {
+            meta.attr("name")
+                .or_else(|| meta.attr("property"))
+                .is_some_and(|n| n.eq_ignore_ascii_case(name))
+        })
+        .filter_map(|meta| meta.attr("content"))
+        .map(|content| content.trim().to_string())
+        .filter(|content| !content.is_empty())
+        .collect()
+}
+
+fn select_text(document: &Html, selector: &str) -> Option<String> {
+    let selector = Selector::parse(selector).expect("Selector is valid");
+    let text = document
+        .select(&selector)
+        .next()?
+        .text()
+        .collect::<String>();
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    (!text.is_empty()).then_some(text)
+}
+
+fn select_attr(document: &Html, selector: &str, attr: &str) -> Option<String> {
+    let selector = Selector::parse(selector).expect("Selector is valid");
+    document
+        .select(&selector)
+        .find_map(|element| element.attr(attr))
+        .map(|value| value.trim().to_string())
+}
+
+/// Finds the main content element and renders its text.
+fn extract_text(document: &Html) -> Option<String> {
+    let root = document.root_element();
+
+    let mut scores: HashMap<_, f64> = HashMap::new();
+    for element in root.descendent_elements() {
+        if !SCORED_TAGS.contains(&element.value().name()) || is_unlikely(&element) {
+            continue;
+        }
+        let text: String = element.text().collect();
+        let text = text.trim();
+        if text.chars().count() < MIN_PARAGRAPH_LENGTH {
+            continue;
+        }
+        let score = 1.0
+            + text.matches([',', '，']).count() as f64
+            + (text.chars().count() as f64 / 100.0).min(3.0);
+        let ancestors = element.ancestors().filter_map(ElementRef::wrap).take(2);
+        for (level, ancestor) in ancestors.enumerate() {
+            if is_unlikely(&ancestor) {
+                break;
+            }
+            let initial = class_weight(&ancestor);
+            let divider = if level == 0 { 1.0 } else { 2.0 };
+            *scores.entry(ancestor.id()).or_insert(initial) += score / divider;
+        }
+    }
+
+    let best = scores
+        .into_iter()
+        .filter_map(|(id, score)| {
+            let element = ElementRef::wrap(document.tree.get(id)?)?;
+            Some((element, score * (1.0 - link_density(&element))))
+        })
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))?
+        .0;
+
+    let mut text = String::new();
+    render(&best, &mut text);
+    let text = text
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    (!text.is_empty()).then_some(text)
+}
+
+/// Whether the element should be skipped entirely based on its tag, class or id.
+fn is_unlikely(element: &ElementRef) -> bool {
+    let name = element.value().name();
+    if SKIPPED_TAGS.contains(&name) {
+        return true;
+    }
+    if name == "body" || name == "article" || name == "main" {
+        return false;
+    }
+    let hints = class_and_id(element);
+    NEGATIVE_HINTS.iter().any(|hint| hints.contains(hint))
+        && !POSITIVE_HINTS.iter().any(|hint| hints.contains(hint))
+}
+
+/// Initial score of an element based on its class and id.
+fn class_weight(element: &ElementRef) -> f64 {
+    let hints = class_and_id(element);
+    let mut weight = 0.0;
+    if POSITIVE_HINTS.iter().any(|hint| hints.contains(hint)) {
+        weight += 25.0;
+    }
+    if NEGATIVE_HINTS.iter().any(|hint| hints.contains(hint)) {
+        weight -= 25.0;
+    }
+    if matches!(element.value().name(), "article" | "main") {
+        weight += 10.0;
+    }
+    weight
+}
+
+fn class_and_id(element: &ElementRef) -> String {
+    format!(
+        "{} {}",
+        element.value().attr("class").unwrap_or_default(),
+        element.value().attr("id").unwrap_or_default()
+    )
+    .to_lowercase()
+}
+
+/// Fraction of the element's text that is inside links.
+fn link_density(element: &ElementRef) -> f64 {
+    let text_length: usize = element.text().map(str::len).sum();
+    if text_length == 0 {
+        return 1.0;
+    }
+    let link_length: usize = element
+        .descendent_elements()
+        .filter(|e| e.value().name() == "a")
+        .flat_map(|e| e.text())
+        .map(str::len)
+        .sum();
+    link_length as f64 / text_length as f64
+}
+
+/// Appends the text of an element to `out`, skipping unlikely elements and link-heavy blocks.
+fn render(element: &ElementRef, out: &mut String) {
+    for child in element.children() {
+        match child.value() {
+            Node::Text(text) => out.push_str(text),
+            Node::Element(_) => {
+                let Some(child) = ElementRef::wrap(child) else {
+                    continue;
+                };
+                if is_unlikely(&child) {
+                    continue;
+                }
+                let is_block = BLOCK_TAGS.contains(&child.value().name());
+                if is_block
+                    && matches!(child.value().name(), "ul" | "ol" | "table" | "div")
+                    && link_density(&child) > 0.5
+                {
+                    continue;
+                }
+                if is_block {
+                    out.push('\n');
+                }
+                render(&child, out);
+                if is_block {
+                    out.push('\n');
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{extract, extract_with_metadata};
+
+    #[test]
+    fn extracts_article_and_skips_boilerplate() {
+        let html = r##"<html><head><title>Title</title><script>var x = 1;</script>
+<meta name="description" content="A description"><link rel="canonical" href="https://example.com/a">
+<meta property="article:tag" content="news"><meta name="keywords" content="a, b"></head>
+<body>
+  <nav><a href="/">Home</a> <a href="/about">About</a></nav>
+  <div class="sidebar"><p>Subscribe to our newsletter, it is great, really great.</p></div>
+  <div id="main-content">
+    <h1>A headline</h1>
+    <p>The first paragraph of the article has enough text, and it also has commas, to be scored.</p>
+    <p>The second paragraph <b>continues</b> the story, with even more words in it.</p>
+    <ul class="share"><li><a href="#">Share</a></li><li><a href="#">Tweet</a></li></ul>
+  </div>
+  <footer>Copyright 2024, all rights reserved, by a company.</footer>
+</body></html>"##;
+        let document = extract_with_metadata(html).unwrap();
+        assert_eq!(document.title.as_deref(), Some("Title"));
+        assert_eq!(document.description.as_deref(), Some("A description"));
+        assert_eq!(
+            document.canonical_url.as_deref(),
+            Some("https://example.com/a")
+        );
+        assert_eq!(document.tags, vec!["news", "a", "b"]);
+        assert_eq!(
+            document.text,
+            "A headline\nThe first paragraph of the article has enough text, and it also has commas, to be scored.\nThe second paragraph continues the story, with even more words in it."
+        );
+    }
+
+    #[test]
+    fn returns_none_without_content() {
+        assert_eq!(
+            extract("<html><body><a href=\"/\">Home</a></body></html>"),
+            None
+        );
+    }
+}
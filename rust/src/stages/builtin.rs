@@ -0,0 +1,199 @@
+//! The built-in stages of the document pipeline, see [super::StageKind].
+//!
+//! Most stages are implemented directly on the type that is built from their config section,
+//! e.g. the `text_cleaning` stage is a [TextCleaningPipeline].
+use std::sync::Arc;
+
+use warc::WarcHeader;
+
+use super::{Document, Stage, StageOutcome};
+use crate::{
+    cleaning::TextCleaningPipeline,
+    encoding::decode_html,
+    extractor::Extractor,
+    filters::{bad_words::BadWordsFilter, FilterFailure, QualityFilterConfig},
+    langid::LanguageFilter,
+    perplexity::PerplexityScorer,
+    pii::PiiConfig,
+    prefilter::PrefilterConfig,
+    tokenizer::DocumentTokenizer,
+};
+
+/// Documents without failures continue, all others fail.
+fn outcome(failures: Vec<FilterFailure>) -> StageOutcome {
+    if failures.is_empty() {
+        StageOutcome::Continue
+    } else {
+        StageOutcome::Failed(failures)
+    }
+}
+
+impl Stage for PrefilterConfig {
+    fn name(&self) -> &'static str {
+        "prefilter"
+    }
+
+    fn process(&self, document: &mut Document) -> Result<StageOutcome, anyhow::Error> {
+        let identified_payload_type = document.warc_header(WarcHeader::IdentifiedPayloadType);
+        match self.check_response(&document.response, identified_payload_type) {
+            Ok(()) => Ok(StageOutcome::Continue),
+            Err(rejection) => Ok(StageOutcome::Drop(rejection.as_str())),
+        }
+    }
+}
+
+/// Decodes the payload into HTML, see [decode_html].
+pub struct DecodeStage;
+
+impl Stage for DecodeStage {
+    fn name(&self) -> &'static str {
+        "decode"
+    }
+
+    fn process(&self, document: &mut Document) -> Result<StageOutcome, anyhow::Error> {
+        let html = decode_html(
+            &document.response.payload,
+            document.response.content_type(),
+            document.entry.metadata.charset.as_deref(),
+        )
+        .html;
+        tracing::debug!(
+            "First 2000 characters of raw content: {}",
+            &html[..html.char_indices().nth(2000).map_or(html.len(), |(i, _)| i)]
+        );
+        document.html = Some(html);
+        Ok(StageOutcome::Continue)
+    }
+}
+
+/// Extracts the text and metadata from the HTML with an [Extractor].
+pub struct ExtractStage(pub Arc<dyn Extractor>);
+
+impl Stage for ExtractStage {
+    fn name(&self) -> &'static str {
+        "extract"
+    }
+
+    fn process(&self, document: &mut Document) -> Result<StageOutcome, anyhow::Error> {
+        let html = document.html.as_deref().ok_or_else(|| {
+            anyhow::anyhow!("The document has no HTML, run the decode stage first")
+        })?;
+        let extracted = self
+            .0
+            .extract(html, document.warc_header(WarcHeader::TargetURI))?;
+        let Some(extracted) = extracted else {
+            tracing::warn!("Failed to extract content from WARC entry");
+            return Ok(StageOutcome::Drop("no_text"));
+        };
+        tracing::info!("Extracted content of length {}", extracted.text.len());
+        tracing::debug!("Extracted content: {}", extracted.text);
+        document.extracted = Some(extracted);
+        Ok(StageOutcome::Continue)
+    }
+}
+
+impl Stage for TextCleaningPipeline {
+    fn name(&self) -> &'static str {
+        "text_cleaning"
+    }
+
+    /// In [crate::filters::FilterMode::Annotate], a document that a cleaning stage dropped keeps its uncleaned text.
+    fn process(&self, document: &mut Document) -> Result<StageOutcome, anyhow::Error> {
+        let extracted = document.extracted_mut()?;
+        match self.clean(&extracted.text) {
+            Ok(text) => {
+                extracted.text = text;
+                Ok(StageOutcome::Continue)
+            }
+            Err(failure) => Ok(StageOutcome::Failed(vec![failure])),
+        }
+    }
+}
+
+impl Stage for LanguageFilter {
+    fn name(&self) -> &'static str {
+        "language_id"
+    }
+
+    fn process(&self, document: &mut Document) -> Result<StageOutcome, anyhow::Error> {
+        let (prediction, failure) = self.check(&document.extracted()?.text);
+        document.language = prediction;
+        Ok(outcome(failure.into_iter().collect()))
+    }
+}
+
+impl Stage for QualityFilterConfig {
+    fn name(&self) -> &'static str {
+        "quality_filters"
+    }
+
+    fn process(&self, document: &mut Document) -> Result<StageOutcome, anyhow::Error> {
+        let language = document.language.as_ref().map(|l| l.language.as_str());
+        Ok(outcome(self.check(&document.extracted()?.text, language)))
+    }
+}
+
+impl Stage for BadWordsFilter {
+    fn name(&self) -> &'static str {
+        "bad_words"
+    }
+
+    fn process(&self, document: &mut Document) -> Result<StageOutcome, anyhow::Error> {
+        let language = document.language.as_ref().map(|l| l.language.as_str());
+        Ok(outcome(self.check(
+            document.url(),
+            &document.extracted()?.text,
+            language,
+        )))
+    }
+}
+
+impl Stage for PerplexityScorer {
+    fn name(&self) -> &'static str {
+        "perplexity"
+    }
+
+    fn process(&self, document: &mut Document) -> Result<StageOutcome, anyhow::Error> {
+        let language = document.language.as_ref().map(|l| l.language.as_str());
+        document.perplexity = self.score(&document.extracted()?.text, language);
+        Ok(StageOutcome::Continue)
+    }
+}
+
+impl Stage for PiiConfig {
+    fn name(&self) -> &'static str {
+        "pii"
+    }
+
+    fn process(&self, document: &mut Document) -> Result<StageOutcome, anyhow::Error> {
+        let extracted = document.extracted_mut()?;
+        let (text, spans) = self.redact(&extracted.text);
+        extracted.text = text;
+        if self.record_spans {
+            document.pii_spans = spans;
+        }
+        Ok(StageOutcome::Continue)
+    }
+}
+
+/// Encodes the final text with a [DocumentTokenizer] and keeps the token IDs followed by the EOS token
+/// for the token shards of the worker.
+pub struct TokenizeStage(pub DocumentTokenizer);
+
+impl Stage for TokenizeStage {
+    fn name(&self) -> &'static str {
+        "tokenize"
+    }
+
+    fn process(&self, document: &mut Document) -> Result<StageOutcome, anyhow::Error> {
+        let mut ids = self
+            .0
+            .encode(&[&document.extracted()?.text])?
+            .pop()
+            .unwrap_or_default();
+        document.num_tokens = Some(ids.len());
+        ids.extend(self.0.eos_token_id());
+        document.token_ids = Some(ids);
+        Ok(StageOutcome::Continue)
+    }
+}
@@ -0,0 +1,668 @@
+//! This module contains the document pipeline of the worker, which turns a downloaded WARC record into an output record.
+//!
+//! Every processing step implements [Stage]. A stage gets a mutable [Document], which carries the cdx entry,
+//! the WARC headers, the HTTP response, the decoded HTML, the extracted text and the annotations of earlier stages,
+//! and decides whether the document continues, fails a filter or is dropped. The [Pipeline] runs the stages
+//! in the order of the [PipelineConfig], measures the duration of every stage in the `worker_stage_duration_seconds`
+//! histogram and counts the documents that every stage rejects in the `worker_stage_rejections` metric.
+//!
+//! The built-in stages are listed in [StageKind] and implemented in [builtin], and their config sections are part of
+//! [StageConfigs]. To add a filter, implement [Stage], add a [StageKind] and a config section for it and build it
+//! in [StageKind::build]. The worker builds its pipeline with [Pipeline::from_config] and needs no changes.
+//! Stages can store their results in [Document::annotations], which are written to the output as they are.
+use std::{collections::BTreeMap, fmt, sync::Arc};
+
+use lazy_static::lazy_static;
+use prometheus::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+use serde::{Deserialize, Serialize};
+use warc::WarcHeader;
+
+use crate::{
+    cleaning::{TextCleaningConfig, TextCleaningPipeline},
+    commoncrawl::CdxEntry,
+    extractor::{ExtractedDocument, Extractor},
+    filters::{
+        bad_words::BadWordsFilter, count_failures, FilterFailure, FilterMode, QualityFilterConfig,
+    },
+    http::{parse_http_response, HttpResponse},
+    langid::{LanguageFilter, LanguageIdConfig, LanguagePrediction},
+    output::OutputRecord,
+    perplexity::{PerplexityConfig, PerplexityScore, PerplexityScorer},
+    pii::{PiiConfig, PiiSpan},
+    prefilter::PrefilterConfig,
+    tokenizer::{DocumentTokenizer, TokenizerConfig},
+};
+use builtin::{DecodeStage, ExtractStage, TokenizeStage};
+
+pub mod builtin;
+
+lazy_static! {
+    static ref STAGE_DURATION_HISTOGRAM: HistogramVec = register_histogram_vec!(
+        "worker_stage_duration_seconds",
+        "Time that a stage of the document pipeline spent on a document, per stage",
+        &["stage"],
+        vec![0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0]
+    )
+    .unwrap();
+    static ref STAGE_REJECTIONS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "worker_stage_rejections",
+        "Number of documents that a stage of the document pipeline dropped, per stage and reason",
+        &["stage", "reason"]
+    )
+    .unwrap();
+}
+
+/// The reason with which documents that failed a filter are counted in [count_rejection].
+/// The rules that they failed are counted in the `quality_filter_failures` metric, see [count_failures].
+const FAILED_FILTER_REASON: &str = "failed_filter";
+
+/// Counts a document that a stage dropped in the `worker_stage_rejections` metric.
+/// Documents that are only annotated in [FilterMode::Annotate] are not counted.
+pub fn count_rejection(stage: &str, reason: &str) {
+    STAGE_REJECTIONS_COUNTER
+        .with_label_values(&[stage, reason])
+        .inc();
+}
+
+/// A document on its way through the [Pipeline].
+#[derive(Debug, Clone)]
+pub struct Document {
+    /// The cdx entry of the WARC record.
+    pub entry: CdxEntry,
+    /// The headers of the WARC `response` record, by name, e.g. `WARC-Target-URI`.
+    pub warc_headers: BTreeMap<String, String>,
+    /// The HTTP response of the record, including the payload.
+    pub response: HttpResponse,
+    /// The HTML of the payload, set by the `decode` stage.
+    pub html: Option<String>,
+    /// The extracted text and metadata, set by the `extract` stage.
+    pub extracted: Option<ExtractedDocument>,
+    /// The language of the text, set by the `language_id` stage.
+    pub language: Option<LanguagePrediction>,
+    /// The perplexity of the text, set by the `perplexity` stage.
+    pub perplexity: Option<PerplexityScore>,
+    /// The locations of the placeholders of redacted personal data, set by the `pii` stage.
+    pub pii_spans: Vec<PiiSpan>,
+    /// The number of tokens of the final text, set by the `tokenize` stage.
+    pub num_tokens: Option<usize>,
+    /// The token IDs of the final text followed by the EOS token, if one is configured, set by the `tokenize` stage.
+    pub token_ids: Option<Vec<u32>>,
+    /// The rules that the document failed. Only non-empty in [FilterMode::Annotate].
+    pub quality_failures: Vec<FilterFailure>,
+    /// Additional results of stages, which are written to the `annotations` field of the output.
+    pub annotations: BTreeMap<String, serde_json::Value>,
+}
+
+impl Document {
+    /// Reads the `response` record from the downloaded WARC data and parses its HTTP response.
+    /// Returns `Ok(None)` if the data contains no `response` record. Compressed payloads are
+    /// decoded up to one byte more than `max_payload_bytes`.
+    pub fn from_warc_record(
+        entry: CdxEntry,
+        data: &[u8],
+        max_payload_bytes: usize,
+    ) -> Result<Option<Self>, StageError> {
+        for record in warc::WarcReader::new(data).iter_records() {
+            let record = record.map_err(|e| StageError::new("warc_parse", e.into()))?;
+            if record.header(WarcHeader::WarcType).as_deref() != Some("response") {
+                continue;
+            }
+            let (header, body) = record.into_raw_parts();
+            let warc_headers: BTreeMap<String, String> = header
+                .headers
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), String::from_utf8_lossy(&value).into()))
+                .collect();
+            let identified_payload_type =
+                warc_headers.get(&WarcHeader::IdentifiedPayloadType.to_string());
+            let response = parse_http_response(
+                &body,
+                identified_payload_type.map(String::as_str),
+                max_payload_bytes,
+            )
+            .map_err(|e| StageError::new("http_parse", e))?;
+            let mut annotations = BTreeMap::new();
+            if response.truncated {
+                annotations.insert("truncated_payload".to_string(), serde_json::json!(true));
+            }
+            let document = Self {
+                entry,
+                warc_headers,
+                response,
+                html: None,
+                extracted: None,
+                language: None,
+                perplexity: None,
+                pii_spans: Vec::new(),
+                num_tokens: None,
+                token_ids: None,
+                quality_failures: Vec::new(),
+                annotations,
+            };
+            tracing::info!(
+                "Successfully read WARC entry with URL {}",
+                document
+                    .warc_header(WarcHeader::TargetURI)
+                    .unwrap_or_default()
+            );
+            return Ok(Some(document));
+        }
+        Ok(None)
+    }
+
+    /// Returns the value of a header of the WARC record.
+    pub fn warc_header(&self, header: WarcHeader) -> Option<&str> {
+        self.warc_headers
+            .get(&header.to_string())
+            .map(String::as_str)
+    }
+
+    /// The URL of the document, from the cdx index.
+    pub fn url(&self) -> &str {
+        &self.entry.metadata.url
+    }
+
+    /// The extracted document. Fails for stages that run before the `extract` stage.
+    pub fn extracted(&self) -> Result<&ExtractedDocument, anyhow::Error> {
+        self.extracted.as_ref().ok_or_else(no_extracted_text)
+    }
+
+    pub fn extracted_mut(&mut self) -> Result<&mut ExtractedDocument, anyhow::Error> {
+        self.extracted.as_mut().ok_or_else(no_extracted_text)
+    }
+
+    /// Converts the document into the record that the worker writes. Returns `None` if no text was extracted.
+    pub fn into_output_record(self) -> Option<OutputRecord> {
+        let extracted = self.extracted?;
+        Some(OutputRecord {
+            quality_failures: self
+                .quality_failures
+                .iter()
+                .map(ToString::to_string)
+                .collect(),
+            language: self.language.as_ref().map(|l| l.language.clone()),
+            language_confidence: self.language.map(|l| l.confidence),
+            pii_spans: self.pii_spans,
+            num_tokens: self.num_tokens,
+            token_ids: self.token_ids,
+            perplexity: self.perplexity.as_ref().map(|score| score.perplexity),
+            quality_tier: self.perplexity.as_ref().and_then(|score| score.tier),
+            paragraph_perplexities: self
+                .perplexity
+                .map(|score| score.paragraph_perplexities)
+                .unwrap_or_default(),
+            annotations: self.annotations,
+            ..OutputRecord::new(&self.entry, extracted)
+        })
+    }
+}
+
+fn no_extracted_text() -> anyhow::Error {
+    anyhow::anyhow!(
+        "The document has no extracted text, the stage has to run after the extract stage"
+    )
+}
+
+/// What happens to a document after a stage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StageOutcome {
+    /// The document continues with the next stage.
+    Continue,
+    /// The document failed rules of a filter. Depending on the [FilterMode], it is dropped or annotated with them.
+    Failed(Vec<FilterFailure>),
+    /// The document is dropped independent of the [FilterMode], for the given reason, e.g. because it is not HTML.
+    Drop(&'static str),
+}
+
+/// A step of the document pipeline.
+pub trait Stage: Send + Sync {
+    /// The name of the stage, used as a metrics label.
+    fn name(&self) -> &'static str;
+
+    /// Processes the document. Errors are counted per stage in the `worker_failed_records` metric of the worker.
+    fn process(&self, document: &mut Document) -> Result<StageOutcome, anyhow::Error>;
+}
+
+/// An error in a stage of the document pipeline.
+#[derive(Debug)]
+pub struct StageError {
+    pub stage: &'static str,
+    pub error: anyhow::Error,
+}
+
+impl StageError {
+    pub fn new(stage: &'static str, error: anyhow::Error) -> Self {
+        Self { stage, error }
+    }
+}
+
+impl fmt::Display for StageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} failed: {:#}", self.stage, self.error)
+    }
+}
+
+impl std::error::Error for StageError {}
+
+/// The built-in stages of the worker. Their parameters are configured in the section of the worker config
+/// with the same name, except for `bad_words`, which is configured in `quality_filters.bad_words`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StageKind {
+    /// Skips responses that are too large or not HTML.
+    Prefilter,
+    /// Decodes the payload into HTML.
+    Decode,
+    /// Extracts the text and metadata from the HTML.
+    Extract,
+    TextCleaning,
+    LanguageId,
+    QualityFilters,
+    BadWords,
+    Perplexity,
+    Pii,
+    /// Counts the tokens of the final text and keeps them for the token shards.
+    Tokenize,
+}
+
+impl StageKind {
+    /// All stages, in their default order.
+    pub const ALL: [StageKind; 10] = [
+        StageKind::Prefilter,
+        StageKind::Decode,
+        StageKind::Extract,
+        StageKind::TextCleaning,
+        StageKind::LanguageId,
+        StageKind::QualityFilters,
+        StageKind::BadWords,
+        StageKind::Perplexity,
+        StageKind::Pii,
+        StageKind::Tokenize,
+    ];
+
+    /// Builds the stage from its config section. Returns `Ok(None)` if the stage is disabled in its section,
+    /// e.g. the `perplexity` stage if no language model is configured.
+    pub fn build(
+        &self,
+        configs: &StageConfigs,
+        extractor: &Arc<dyn Extractor>,
+    ) -> Result<Option<Box<dyn Stage>>, anyhow::Error> {
+        fn boxed(stage: impl Stage + 'static) -> Option<Box<dyn Stage>> {
+            Some(Box::new(stage))
+        }
+        Ok(match self {
+            StageKind::Prefilter => boxed(configs.prefilter.clone()),
+            StageKind::Decode => boxed(DecodeStage),
+            StageKind::Extract => boxed(ExtractStage(extractor.clone())),
+            StageKind::TextCleaning => {
+                boxed(TextCleaningPipeline::from_config(&configs.text_cleaning))
+            }
+            StageKind::LanguageId => boxed(LanguageFilter::from_config(&configs.language_id)?),
+            StageKind::QualityFilters => boxed(configs.quality_filters.clone()),
+            StageKind::BadWords => {
+                BadWordsFilter::from_config(&configs.quality_filters.bad_words)?.and_then(boxed)
+            }
+            StageKind::Perplexity => {
+                PerplexityScorer::from_config(&configs.perplexity)?.and_then(boxed)
+            }
+            StageKind::Pii => configs
+                .pii
+                .enabled
+                .then(|| configs.pii.clone())
+                .and_then(boxed),
+            StageKind::Tokenize => DocumentTokenizer::from_config(&configs.tokenizer)?
+                .map(TokenizeStage)
+                .and_then(boxed),
+        })
+    }
+
+    /// The input that the stage needs. Stages have to run in the order of their inputs.
+    fn input(&self) -> usize {
+        match self {
+            StageKind::Prefilter | StageKind::Decode => 0,
+            StageKind::Extract => 1,
+            StageKind::Tokenize => 3,
+            _ => 2,
+        }
+    }
+}
+
+/// The config sections of the built-in stages, which are sections of the worker config with the same name.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StageConfigs {
+    /// Checks that decide whether a record is extracted at all.
+    pub prefilter: PrefilterConfig,
+    /// Stages that clean the extracted text before the quality filters run.
+    pub text_cleaning: TextCleaningConfig,
+    /// Identification of the language of the extracted text.
+    pub language_id: LanguageIdConfig,
+    /// Quality filters that are applied to the extracted text.
+    pub quality_filters: QualityFilterConfig,
+    /// Perplexity scoring with n-gram language models and quality tiers.
+    pub perplexity: PerplexityConfig,
+    /// Redaction of personal data such as email addresses and phone numbers.
+    pub pii: PiiConfig,
+    /// Tokenization of the output for LLM training.
+    pub tokenizer: TokenizerConfig,
+}
+
+/// Configuration of the document pipeline.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PipelineConfig {
+    /// The stages in the order in which they run. Stages that are disabled in their section are skipped.
+    pub stages: Vec<StageKind>,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        Self {
+            stages: StageKind::ALL.to_vec(),
+        }
+    }
+}
+
+impl PipelineConfig {
+    /// Checks that the stages `decode` and `extract` are configured, that no stage is configured twice,
+    /// and that the stages that work on the payload, the HTML and the text and `tokenize` run in this order.
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        for required in [StageKind::Decode, StageKind::Extract] {
+            anyhow::ensure!(
+                self.stages.contains(&required),
+                "The pipeline needs the stage {:?}",
+                required
+            );
+        }
+        for (i, stage) in self.stages.iter().enumerate() {
+            anyhow::ensure!(
+                !self.stages[..i].contains(stage),
+                "The stage {:?} is configured twice",
+                stage
+            );
+        }
+        let decode = self.stages.iter().position(|s| *s == StageKind::Decode);
+        let prefilter = self.stages.iter().position(|s| *s == StageKind::Prefilter);
+        anyhow::ensure!(
+            self.stages.is_sorted_by_key(StageKind::input) && prefilter.is_none_or(|p| Some(p) < decode),
+            "The stages have to run in the order prefilter, decode, extract, the text stages and tokenize, not {:?}",
+            self.stages
+        );
+        Ok(())
+    }
+}
+
+/// Runs a sequence of [Stage]s on documents.
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+    mode: FilterMode,
+}
+
+impl Pipeline {
+    /// Creates a pipeline that handles documents that fail a filter according to `mode`.
+    pub fn new(stages: Vec<Box<dyn Stage>>, mode: FilterMode) -> Self {
+        Self { stages, mode }
+    }
+
+    /// Builds the stages of the config in their order, skipping the stages that are disabled in their section.
+    /// Documents that fail a filter are handled according to the mode of the quality filters.
+    pub fn from_config(
+        config: &PipelineConfig,
+        stage_configs: &StageConfigs,
+        extractor: Arc<dyn Extractor>,
+    ) -> Result<Self, anyhow::Error> {
+        config.validate()?;
+        let mut stages = Vec::new();
+        for kind in &config.stages {
+            stages.extend(kind.build(stage_configs, &extractor)?);
+        }
+        Ok(Self::new(stages, stage_configs.quality_filters.mode))
+    }
+
+    /// The names of the stages, in order.
+    pub fn stage_names(&self) -> Vec<&'static str> {
+        self.stages.iter().map(|stage| stage.name()).collect()
+    }
+
+    /// Runs all stages on the document. Returns `Ok(None)` if a stage dropped the document,
+    /// or if it failed a filter and the pipeline runs in [FilterMode::Drop].
+    /// In [FilterMode::Annotate], documents that fail a filter continue with the next stage.
+    pub fn run(&self, mut document: Document) -> Result<Option<Document>, StageError> {
+        for stage in &self.stages {
+            let timer = STAGE_DURATION_HISTOGRAM
+                .with_label_values(&[stage.name()])
+                .start_timer();
+            let outcome = stage
+                .process(&mut document)
+                .map_err(|e| StageError::new(stage.name(), e))?;
+            timer.observe_duration();
+            match outcome {
+                StageOutcome::Continue => {}
+                StageOutcome::Drop(reason) => {
+                    count_rejection(stage.name(), reason);
+                    tracing::debug!(
+                        url = document.url(),
+                        stage = stage.name(),
+                        reason,
+                        "Dropping document"
+                    );
+                    return Ok(None);
+                }
+                StageOutcome::Failed(failures) => {
+                    let Some(failure) = failures.first() else {
+                        continue;
+                    };
+                    tracing::debug!(url = document.url(), rule = %failure, "Document failed quality filter");
+                    count_failures(&failures, self.mode);
+                    if self.mode == FilterMode::Drop {
+                        count_rejection(stage.name(), FAILED_FILTER_REASON);
+                        return Ok(None);
+                    }
+                    document.quality_failures.extend(failures);
+                }
+            }
+        }
+        Ok(Some(document))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{
+        cleaning::{TextCleaningConfig, TextCleaningPipeline},
+        commoncrawl::parse_cdx_line,
+        extractor::ReadabilityExtractor,
+        filters::{FilterFailure, FilterMode, QualityFilterConfig},
+    };
+
+    use super::{
+        builtin::{DecodeStage, ExtractStage},
+        Document, Pipeline, PipelineConfig, Stage, StageConfigs, StageKind, StageOutcome,
+        STAGE_REJECTIONS_COUNTER,
+    };
+
+    /// Fails documents whose payload contains a word and annotates all others.
+    struct KeywordStage(&'static str);
+
+    impl Stage for KeywordStage {
+        fn name(&self) -> &'static str {
+            "keyword"
+        }
+
+        fn process(&self, document: &mut Document) -> Result<StageOutcome, anyhow::Error> {
+            let payload = String::from_utf8_lossy(&document.response.payload);
+            if payload.contains(self.0) {
+                return Ok(StageOutcome::Failed(vec![FilterFailure {
+                    filter: "keyword",
+                    rule: "contains_keyword",
+                }]));
+            }
+            document
+                .annotations
+                .insert("keyword".to_string(), serde_json::json!(false));
+            Ok(StageOutcome::Continue)
+        }
+    }
+
+    /// Parses a WARC `response` record with an HTML payload into a document.
+    fn document(payload: &str) -> Document {
+        let entry = parse_cdx_line(
+            r#"org,example)/ 20240722120756 {"url": "https://example.org/", "mime": "text/html", "mime-detected": "text/html", "status": "200", "digest": "ABC", "length": "100", "offset": "0", "filename": "crawl-data/example.warc.gz", "languages": "eng"}"#,
+        );
+        let http = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/html\r\nContent-Length: {}\r\n\r\n{}",
+            payload.len(),
+            payload
+        );
+        let warc = format!(
+            "WARC/1.0\r\nWARC-Type: response\r\nWARC-Target-URI: https://example.org/\r\nWARC-Date: 2024-07-22T12:07:56Z\r\nWARC-Record-ID: <urn:uuid:00000000-0000-0000-0000-000000000000>\r\nContent-Type: application/http; msgtype=response\r\nContent-Length: {}\r\n\r\n{}\r\n\r\n",
+            http.len(),
+            http
+        );
+        Document::from_warc_record(entry, warc.as_bytes(), 1 << 20)
+            .unwrap()
+            .unwrap()
+    }
+
+    #[test]
+    fn builds_default_pipeline() {
+        // Stages that are disabled or that need a model or lexicon are skipped by default.
+        let pipeline = Pipeline::from_config(
+            &PipelineConfig::default(),
+            &StageConfigs::default(),
+            Arc::new(ReadabilityExtractor),
+        )
+        .unwrap();
+        assert_eq!(
+            pipeline.stage_names(),
+            vec![
+                "prefilter",
+                "decode",
+                "extract",
+                "text_cleaning",
+                "language_id",
+                "quality_filters"
+            ]
+        );
+    }
+
+    #[test]
+    fn runs_stages() {
+        let document = document("<html><body>Hello casino</body></html>");
+        assert_eq!(
+            document.warc_header(warc::WarcHeader::TargetURI),
+            Some("https://example.org/")
+        );
+
+        let stages = || -> Vec<Box<dyn Stage>> { vec![Box::new(KeywordStage("casino"))] };
+        let pipeline = Pipeline::new(stages(), FilterMode::Drop);
+        assert!(pipeline.run(document.clone()).unwrap().is_none());
+        let pipeline = Pipeline::new(stages(), FilterMode::Annotate);
+        let annotated = pipeline.run(document.clone()).unwrap().unwrap();
+        assert_eq!(
+            annotated.quality_failures[0].to_string(),
+            "keyword.contains_keyword"
+        );
+        let pipeline = Pipeline::new(vec![Box::new(KeywordStage("poker"))], FilterMode::Drop);
+        let kept = pipeline.run(document).unwrap().unwrap();
+        assert_eq!(kept.annotations["keyword"], serde_json::json!(false));
+
+        assert!(PipelineConfig::default().validate().is_ok());
+        let config = |stages: &[StageKind]| PipelineConfig {
+            stages: stages.to_vec(),
+        };
+        use StageKind::*;
+        assert!(config(&[Decode, Extract, Pii, TextCleaning])
+            .validate()
+            .is_ok());
+        assert!(config(&[Decode, TextCleaning, Extract]).validate().is_err());
+        assert!(config(&[Decode, Prefilter, Extract]).validate().is_err());
+        assert!(config(&[Prefilter, Extract]).validate().is_err());
+        assert!(config(&[Decode, Extract, Pii, Pii]).validate().is_err());
+        assert!(config(&[Decode, Extract, Tokenize, Pii])
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn runs_builtin_stages() {
+        let html = |paragraphs: &[&str]| {
+            format!(
+                "<html><head><title>Example</title></head><body><nav><a href=\"/\">Home</a> | <a href=\"/news\">News</a></nav><article>{}</article></body></html>",
+                paragraphs
+                    .iter()
+                    .map(|p| format!("<p>{}</p>", p))
+                    .collect::<String>()
+            )
+        };
+        let article = html(&[
+            "The history of the city goes back to the Roman period, when a small fort guarded the bridge over the river.",
+            "A settlement grew around the fort and became an important market at the crossing of two trade routes.",
+            "In the Middle Ages, the merchants of the city built a town hall, a cathedral and a wall with seven gates.",
+            "Today, the city is known for its old town, its university and the festival that takes place every summer.",
+            "Visitors can walk along the old wall, climb the tower of the cathedral and relax in one of the many parks.",
+            "Please accept our cookie policy",
+        ]);
+        let spam = html(&["Buy cheap watches online at the best price today!"; 10]);
+        let stages = || -> Vec<Box<dyn Stage>> {
+            vec![
+                Box::new(DecodeStage),
+                Box::new(ExtractStage(Arc::new(ReadabilityExtractor))),
+                Box::new(TextCleaningPipeline::from_config(
+                    &TextCleaningConfig::default(),
+                )),
+                Box::new(QualityFilterConfig::default()),
+            ]
+        };
+        let rejections = || {
+            STAGE_REJECTIONS_COUNTER
+                .with_label_values(&["quality_filters", "failed_filter"])
+                .get()
+        };
+
+        let kept = Pipeline::new(stages(), FilterMode::Drop)
+            .run(document(&article))
+            .unwrap()
+            .unwrap();
+        let text = kept.extracted.unwrap().text;
+        assert!(text.starts_with("The history of the city"), "{}", text);
+        assert!(!text.contains("cookie"));
+        assert!(kept.quality_failures.is_empty());
+
+        let before = rejections();
+        let annotated = Pipeline::new(stages(), FilterMode::Annotate)
+            .run(document(&spam))
+            .unwrap()
+            .unwrap();
+        assert!(annotated
+            .quality_failures
+            .iter()
+            .any(|failure| failure.filter == "repetition"));
+        assert_eq!(rejections(), before);
+        assert!(Pipeline::new(stages(), FilterMode::Drop)
+            .run(document(&spam))
+            .unwrap()
+            .is_none());
+        assert_eq!(rejections(), before + 1);
+
+        // The default pipeline runs the stages in the order in which the worker always processed documents.
+        use StageKind::*;
+        assert_eq!(
+            PipelineConfig::default().stages,
+            [
+                Prefilter,
+                Decode,
+                Extract,
+                TextCleaning,
+                LanguageId,
+                QualityFilters,
+                BadWords,
+                Perplexity,
+                Pii,
+                Tokenize
+            ]
+        );
+    }
+}
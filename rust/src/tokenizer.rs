@@ -0,0 +1,332 @@
+//! This module contains the tokenization stage, which prepares the output of the worker for LLM training.
+//!
+//! The `tokenize` stage encodes the final text of every document with a HuggingFace tokenizer, which is loaded
+//! either from a `tokenizer.json` or from the `vocab.json` and `merges.txt` of a byte-level BPE tokenizer,
+//! and records the number of tokens in the output. Optionally, the worker writes the token IDs of every output shard
+//! into a token shard next to it, so that training does not have to tokenize the corpus again.
+//!
+//! A token shard consists of two files. The `.tokens.bin` file contains the token IDs of all documents
+//! of the output shard, in the same order, as little-endian `u32`s without any separators.
+//! The `.tokens.idx` file contains the magic bytes `PLTOKID1`, the number of documents `n` as a little-endian `u64`
+//! and `n + 1` little-endian `u64` offsets into the token IDs, so that document `i` consists of the tokens
+//! from offset `i` up to offset `i + 1`.
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, IntCounter};
+use serde::{Deserialize, Serialize};
+use tokenizers::{models::bpe::BPE, pre_tokenizers::byte_level::ByteLevel, Tokenizer};
+
+use crate::output::SHARD_EXTENSION;
+
+lazy_static! {
+    static ref TOKENS_COUNTER: IntCounter = register_int_counter!(
+        "tokenized_tokens",
+        "Number of tokens of all documents that were tokenized"
+    )
+    .unwrap();
+    static ref TOKENIZED_DOCUMENTS_COUNTER: IntCounter = register_int_counter!(
+        "tokenized_documents",
+        "Number of documents that were tokenized"
+    )
+    .unwrap();
+}
+
+/// The file extension of the token IDs of a token shard.
+pub const TOKEN_SHARD_EXTENSION: &str = "tokens.bin";
+/// The file extension of the index of a token shard.
+pub const TOKEN_INDEX_EXTENSION: &str = "tokens.idx";
+
+/// The first bytes of a token shard index, which identify the file format.
+const INDEX_MAGIC: &[u8; 8] = b"PLTOKID1";
+
+/// Configuration of the tokenization stage.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenizerConfig {
+    /// Path to a HuggingFace `tokenizer.json`.
+    /// Tokenization is disabled if neither this nor a BPE vocabulary is configured.
+    pub tokenizer_path: Option<PathBuf>,
+    /// Path to the `vocab.json` of a byte-level BPE tokenizer such as the one of GPT-2.
+    pub bpe_vocab_path: Option<PathBuf>,
+    /// Path to the `merges.txt` that belongs to `bpe_vocab_path`.
+    pub bpe_merges_path: Option<PathBuf>,
+    /// Whether special tokens such as a BOS token are added as configured in the `tokenizer.json`.
+    pub add_special_tokens: bool,
+    /// A token that is appended to every document in the token shards, e.g. `<|endoftext|>`.
+    /// It is not included in the token counts of the output.
+    pub eos_token: Option<String>,
+    /// Whether token shards are written next to the output shards.
+    pub write_token_shards: bool,
+}
+
+/// Encodes documents with the tokenizer of a [TokenizerConfig].
+pub struct DocumentTokenizer {
+    tokenizer: Tokenizer,
+    add_special_tokens: bool,
+    eos_token_id: Option<u32>,
+    write_token_shards: bool,
+}
+
+impl DocumentTokenizer {
+    /// Loads the tokenizer of the config. Returns `Ok(None)` if no tokenizer is configured.
+    pub fn from_config(config: &TokenizerConfig) -> Result<Option<Self>, anyhow::Error> {
+        let tokenizer = match (
+            &config.tokenizer_path,
+            &config.bpe_vocab_path,
+            &config.bpe_merges_path,
+        ) {
+            (None, None, None) => return Ok(None),
+            (Some(path), None, None) => Tokenizer::from_file(path).map_err(|e| {
+                anyhow::anyhow!("Failed to load tokenizer {}: {}", path.display(), e)
+            })?,
+            (None, Some(vocab), Some(merges)) => {
+                let bpe = BPE::from_file(&vocab.to_string_lossy(), &merges.to_string_lossy())
+                    .build()
+                    .map_err(|e| {
+                        anyhow::anyhow!("Failed to load BPE vocab {}: {}", vocab.display(), e)
+                    })?;
+                let mut tokenizer = Tokenizer::new(bpe);
+                tokenizer.with_pre_tokenizer(Some(ByteLevel::new(false, true, true)));
+                tokenizer.with_decoder(Some(ByteLevel::default()));
+                tokenizer
+            }
+            _ => anyhow::bail!(
+                "Configure either tokenizer_path or both bpe_vocab_path and bpe_merges_path"
+            ),
+        };
+        let eos_token_id = config
+            .eos_token
+            .as_ref()
+            .map(|token| {
+                tokenizer
+                    .token_to_id(token)
+                    .with_context(|| format!("EOS token {} is not in the vocabulary", token))
+            })
+            .transpose()?;
+        tracing::info!(
+            "Loaded tokenizer with a vocabulary of {} tokens",
+            tokenizer.get_vocab_size(true)
+        );
+        Ok(Some(Self {
+            tokenizer,
+            add_special_tokens: config.add_special_tokens,
+            eos_token_id,
+            write_token_shards: config.write_token_shards,
+        }))
+    }
+
+    /// Whether token shards should be written next to the output shards.
+    pub fn write_token_shards(&self) -> bool {
+        self.write_token_shards
+    }
+
+    /// The ID of the token that is appended to every document in the token shards, if one is configured.
+    pub fn eos_token_id(&self) -> Option<u32> {
+        self.eos_token_id
+    }
+
+    /// Encodes the texts and returns their token IDs.
+    /// The tokens are counted in the `tokenized_tokens` metric.
+    pub fn encode(&self, texts: &[&str]) -> Result<Vec<Vec<u32>>, anyhow::Error> {
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts.to_vec(), self.add_special_tokens)
+            .map_err(|e| anyhow::anyhow!("Failed to tokenize documents: {}", e))?;
+        let ids: Vec<Vec<u32>> = encodings
+            .into_iter()
+            .map(|encoding| encoding.get_ids().to_vec())
+            .collect();
+        TOKENIZED_DOCUMENTS_COUNTER.inc_by(ids.len() as u64);
+        TOKENS_COUNTER.inc_by(ids.iter().map(Vec::len).sum::<usize>() as u64);
+        Ok(ids)
+    }
+
+    /// Writes a token shard with the token IDs of the documents to `<path>.tokens.bin` and `<path>.tokens.idx`,
+    /// appending the EOS token to every document if one is configured.
+    pub fn write_token_shard<'a>(
+        &self,
+        path: &Path,
+        documents: impl ExactSizeIterator<Item = &'a [u32]>,
+    ) -> Result<(), anyhow::Error> {
+        let eos: &[u32] = match &self.eos_token_id {
+            Some(id) => std::slice::from_ref(id),
+            None => &[],
+        };
+        write_token_shard(path, documents, eos)
+    }
+}
+
+/// Writes a token shard with the token IDs of the documents, appending `eos` to every document.
+/// Shards that are read with [read_token_shard] already contain their EOS tokens and are written again with an empty `eos`.
+pub fn write_token_shard<'a>(
+    path: &Path,
+    documents: impl ExactSizeIterator<Item = &'a [u32]>,
+    eos: &[u32],
+) -> Result<(), anyhow::Error> {
+    let mut tokens_writer = ShardFileWriter::create(&with_extension(path, TOKEN_SHARD_EXTENSION))?;
+    let mut index_writer = ShardFileWriter::create(&with_extension(path, TOKEN_INDEX_EXTENSION))?;
+    index_writer.writer.write_all(INDEX_MAGIC)?;
+    index_writer
+        .writer
+        .write_all(&(documents.len() as u64).to_le_bytes())?;
+    let mut offset: u64 = 0;
+    index_writer.writer.write_all(&offset.to_le_bytes())?;
+    for document in documents {
+        for id in document.iter().chain(eos) {
+            tokens_writer.writer.write_all(&id.to_le_bytes())?;
+        }
+        offset += (document.len() + eos.len()) as u64;
+        index_writer.writer.write_all(&offset.to_le_bytes())?;
+    }
+    // The index is moved into place last, so that readers never see an index without its tokens.
+    tokens_writer.finish()?;
+    index_writer.finish()
+}
+
+/// Returns the path of the token shard that belongs to an output shard, without the token shard extensions.
+pub fn token_shard_path(shard: &Path) -> PathBuf {
+    let path = shard.to_string_lossy();
+    PathBuf::from(
+        path.strip_suffix(&format!(".{}", SHARD_EXTENSION))
+            .unwrap_or(&path),
+    )
+}
+
+/// Whether the token shard at `path` exists, see [token_shard_path].
+pub fn has_token_shard(path: &Path) -> bool {
+    with_extension(path, TOKEN_INDEX_EXTENSION).exists()
+}
+
+/// Appends `.{extension}` to the path. Unlike [Path::with_extension], this keeps dots in the file name.
+fn with_extension(path: &Path, extension: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(extension);
+    PathBuf::from(path)
+}
+
+/// A file that is written to a temporary path and renamed afterwards, like the output shards.
+struct ShardFileWriter {
+    writer: BufWriter<File>,
+    tmp_path: PathBuf,
+    path: PathBuf,
+}
+
+impl ShardFileWriter {
+    fn create(path: &Path) -> Result<Self, anyhow::Error> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+        }
+        let tmp_path = with_extension(path, "tmp");
+        let file = File::create(&tmp_path)
+            .with_context(|| format!("Failed to create {}", tmp_path.display()))?;
+        Ok(Self {
+            writer: BufWriter::new(file),
+            tmp_path,
+            path: path.to_path_buf(),
+        })
+    }
+
+    fn finish(mut self) -> Result<(), anyhow::Error> {
+        self.writer.flush()?;
+        std::fs::rename(&self.tmp_path, &self.path)
+            .with_context(|| format!("Failed to move token shard to {}", self.path.display()))?;
+        Ok(())
+    }
+}
+
+/// Reads the documents of a token shard that was written with [DocumentTokenizer::write_token_shard].
+/// `path` is the path of the shard without the `.tokens.bin` or `.tokens.idx` extension.
+pub fn read_token_shard(path: &Path) -> Result<Vec<Vec<u32>>, anyhow::Error> {
+    let read = |path: PathBuf| -> Result<Vec<u8>, anyhow::Error> {
+        let mut bytes = Vec::new();
+        BufReader::new(
+            File::open(&path).with_context(|| format!("Failed to open {}", path.display()))?,
+        )
+        .read_to_end(&mut bytes)?;
+        Ok(bytes)
+    };
+    let index = read(with_extension(path, TOKEN_INDEX_EXTENSION))?;
+    anyhow::ensure!(
+        index.len() >= 16 && &index[..8] == INDEX_MAGIC,
+        "{} is not a token shard index",
+        path.display()
+    );
+    let offsets: Vec<usize> = index[16..]
+        .chunks_exact(8)
+        .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()) as usize)
+        .collect();
+    let tokens: Vec<u32> = read(with_extension(path, TOKEN_SHARD_EXTENSION))?
+        .chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+        .collect();
+    anyhow::ensure!(
+        offsets.last() == Some(&tokens.len()),
+        "Token shard {} is truncated",
+        path.display()
+    );
+    Ok(offsets
+        .windows(2)
+        .map(|range| tokens[range[0]..range[1]].to_vec())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::{
+        has_token_shard, read_token_shard, token_shard_path, DocumentTokenizer, TokenizerConfig,
+    };
+
+    #[test]
+    fn tokenizes_and_writes_token_shards() {
+        let dir = std::env::temp_dir().join(format!("tokenizer-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("vocab.json"),
+            r#"{"h": 0, "e": 1, "l": 2, "o": 3, "Ġ": 4, "he": 5, "ll": 6, "hell": 7, "hello": 8, "Ġhello": 9, "<eos>": 10}"#,
+        )
+        .unwrap();
+        std::fs::write(
+            dir.join("merges.txt"),
+            "#version: 0.2\nh e\nl l\nhe ll\nhell o\nĠ hello\n",
+        )
+        .unwrap();
+        let tokenizer = DocumentTokenizer::from_config(&TokenizerConfig {
+            bpe_vocab_path: Some(dir.join("vocab.json")),
+            bpe_merges_path: Some(dir.join("merges.txt")),
+            eos_token: Some("<eos>".to_string()),
+            write_token_shards: true,
+            ..Default::default()
+        })
+        .unwrap()
+        .unwrap();
+        let documents = tokenizer.encode(&["hello hello", "hell"]).unwrap();
+        assert_eq!(documents, vec![vec![8, 9], vec![7]]);
+
+        let path = dir.join("shard");
+        tokenizer
+            .write_token_shard(&path, documents.iter().map(Vec::as_slice))
+            .unwrap();
+        assert_eq!(
+            read_token_shard(&path).unwrap(),
+            vec![vec![8, 9, 10], vec![7, 10]]
+        );
+        assert!(has_token_shard(&token_shard_path(
+            &dir.join("shard.jsonl.gz")
+        )));
+        assert_eq!(
+            token_shard_path(Path::new("output/de/shard.jsonl.gz")),
+            PathBuf::from("output/de/shard")
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
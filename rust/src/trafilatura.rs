@@ -1,58 +1,173 @@
 //! This module contains the Python and PyO3 code to be able to use trafilatura
 //! from Rust.
+//!
+//! The [TrafilaturaConfig] is always available, but calling into Python requires the `trafilatura` cargo feature.
+//! To run trafilatura in isolated subprocesses instead, see [crate::trafilatura_pool].
+#[cfg(feature = "trafilatura")]
 use once_cell::sync::Lazy;
+#[cfg(feature = "trafilatura")]
 use pyo3::{
     ffi::c_str,
-    types::{PyAnyMethods, PyModule},
+    types::{PyAnyMethods, PyDict, PyDictMethods, PyModule},
     Py, PyAny, Python,
 };
-use std::ffi::CStr;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+#[cfg(feature = "trafilatura")]
+use std::ffi::CString;
 
-static PYTHON_SCRIPT: &CStr = c_str!(
-    r"
+#[cfg(feature = "trafilatura")]
+use crate::extractor::ExtractedDocument;
+
+/// The Python code that calls trafilatura. It is loaded into the embedded interpreter via PyO3
+/// and also used by the subprocesses of [crate::trafilatura_pool].
+pub(crate) const PYTHON_SCRIPT: &str = r"
+import json
 from typing import Optional
-from trafilatura import extract
+from trafilatura import bare_extraction
+
+METADATA_KEYS = ('title', 'author', 'date', 'sitename', 'description')
 
-def extract_text(content: str) -> Optional[str]:
-    text = extract(content, include_comments=False,
-                   include_tables=False, deduplicate=True)
+def extract_document_dict(content: str, output_format: str = 'txt', **options) -> Optional[dict]:
+    # bare_extraction parses the page only once and returns the text together with its metadata.
+    if output_format == 'markdown':
+        options['include_formatting'] = True
+    result = bare_extraction(content, include_comments=False, deduplicate=True, with_metadata=True, **options)
+    if result is None:
+        return None
+    result = result.as_dict() if hasattr(result, 'as_dict') else result
+    text = result.get('text')
     # also return None if utf-8 decoding failed
-    if text is None or isinstance(text, bytes):
+    if not text or isinstance(text, bytes):
         return None
-    return text
-"
-);
+    document = {key: result.get(key) for key in METADATA_KEYS}
+    for key in ('categories', 'tags'):
+        document[key] = list(result.get(key) or [])
+    document['canonical_url'] = result.get('url')
+    if output_format == 'xml' and result.get('body') is not None:
+        from lxml.etree import tostring
+        text = tostring(result['body'], encoding='unicode')
+    elif output_format == 'json':
+        text = json.dumps({**document, 'text': text}, ensure_ascii=False)
+    document['text'] = text
+    return document
+
+def extract_document(content: str, **options) -> Optional[str]:
+    document = extract_document_dict(content, **options)
+    return None if document is None else json.dumps(document)
+";
+
+/// The output formats that trafilatura supports.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    #[default]
+    Txt,
+    Markdown,
+    Xml,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OutputFormat::Txt => "txt",
+            OutputFormat::Markdown => "markdown",
+            OutputFormat::Xml => "xml",
+            OutputFormat::Json => "json",
+        }
+    }
+}
 
+/// Options that are passed to trafilatura's `bare_extraction` function as keyword arguments.
+/// See <https://trafilatura.readthedocs.io/en/latest/corefunctions.html#extract> for their meaning.
+/// The `output_format` is applied to the text after extraction.
+/// The defaults correspond to the options that this pipeline has always used.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrafilaturaConfig {
+    pub favor_precision: bool,
+    pub favor_recall: bool,
+    pub include_tables: bool,
+    pub include_links: bool,
+    pub include_images: bool,
+    pub include_formatting: bool,
+    /// Only return documents in this language (ISO 639-1 code).
+    pub target_language: Option<String>,
+    pub output_format: OutputFormat,
+    /// The URL of the document, used by trafilatura for metadata and to resolve relative links.
+    /// The worker sets this per document from the `WARC-Target-URI` header if it is not configured.
+    pub url: Option<String>,
+}
+
+impl TrafilaturaConfig {
+    /// Returns the config for a single document whose URL is `url`.
+    /// A URL that is set in the config takes precedence.
+    pub fn for_document(&self, url: Option<&str>) -> Cow<'_, TrafilaturaConfig> {
+        match (url, &self.url) {
+            (Some(url), None) => Cow::Owned(TrafilaturaConfig {
+                url: Some(url.to_string()),
+                ..self.clone()
+            }),
+            _ => Cow::Borrowed(self),
+        }
+    }
+}
+
+#[cfg(feature = "trafilatura")]
+impl TrafilaturaConfig {
+    /// Converts the config into the keyword arguments for `extract_document` from [PYTHON_SCRIPT].
+    fn to_kwargs<'py>(&self, py: Python<'py>) -> pyo3::PyResult<pyo3::Bound<'py, PyDict>> {
+        let kwargs = PyDict::new(py);
+        kwargs.set_item("favor_precision", self.favor_precision)?;
+        kwargs.set_item("favor_recall", self.favor_recall)?;
+        kwargs.set_item("include_tables", self.include_tables)?;
+        kwargs.set_item("include_links", self.include_links)?;
+        kwargs.set_item("include_images", self.include_images)?;
+        kwargs.set_item("include_formatting", self.include_formatting)?;
+        kwargs.set_item("target_language", self.target_language.as_deref())?;
+        kwargs.set_item("output_format", self.output_format.as_str())?;
+        kwargs.set_item("url", self.url.as_deref())?;
+        Ok(kwargs)
+    }
+}
+
+/// The Python function `extract_document` from [PYTHON_SCRIPT].
+#[cfg(feature = "trafilatura")]
 static PYTHON_EXTRACT_FUNCTION: Lazy<Py<PyAny>> = Lazy::new(|| {
     Python::attach(move |py| -> Py<PyAny> {
         tracing::info!(
             "Loading Python trafilatura with version {:?}.",
             py.version_info()
         );
-        let module = PyModule::from_code(
-            py,
-            PYTHON_SCRIPT,
-            c_str!("extraction.py"),
-            c_str!("extraction"),
-        )
-        .expect("Failed to load Python module");
+        let code = CString::new(PYTHON_SCRIPT).expect("Python script contains no NUL bytes");
+        let module = PyModule::from_code(py, &code, c_str!("extraction.py"), c_str!("extraction"))
+            .expect("Failed to load Python module");
         let extract_function = module
-            .getattr("extract_text")
-            .expect("Failed to get extract_text function");
-        let extract_function = extract_function.into();
+            .getattr("extract_document")
+            .expect("Failed to get extract_document function")
+            .into();
         tracing::info!("Loaded Python trafilatura.");
         extract_function
     })
 });
 
-/// Extract text from `html` and return the extracted
-/// text as string if successful.
+/// Extract text and metadata such as title, author and date from `html` with the given options.
 /// Might return `Ok(None)` if text extraction was not successful.
-pub fn extract(html: &str) -> Result<Option<String>, anyhow::Error> {
-    Python::attach(move |py| -> Result<Option<String>, anyhow::Error> {
+#[cfg(feature = "trafilatura")]
+pub fn extract_with_metadata(
+    html: &str,
+    config: &TrafilaturaConfig,
+) -> Result<Option<ExtractedDocument>, anyhow::Error> {
+    let document = Python::attach(move |py| -> Result<Option<String>, anyhow::Error> {
+        let kwargs = config.to_kwargs(py)?;
         PYTHON_EXTRACT_FUNCTION
-            .call1(py, (html,))?
+            .call(py, (html,), Some(&kwargs))?
             .extract(py)
             .map_err(Into::into)
-    })
+    })?;
+    document
+        .map(|document| serde_json::from_str(&document))
+        .transpose()
+        .map_err(Into::into)
 }
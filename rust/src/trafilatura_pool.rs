@@ -0,0 +1,476 @@
+//! This module runs trafilatura in a pool of Python subprocesses instead of the embedded interpreter.
+//!
+//! A pathological HTML page can make trafilatura run for minutes or use a lot of memory.
+//! In the embedded interpreter, this would stall the whole worker. Here, every document is sent to one
+//! of several long-running Python processes, and a process that does not answer within the timeout
+//! or that crashes, e.g. because it hit its memory limit, is killed and restarted.
+//!
+//! Requests and responses are exchanged over stdin and stdout of the subprocess as frames that consist of
+//! a 4-byte big-endian length followed by a JSON payload of that length.
+//! This mode does not need the `trafilatura` cargo feature, only a Python executable with trafilatura installed.
+use std::{
+    io::{BufReader, Read, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Condvar, Mutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Context;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, register_int_counter_vec, IntCounter, IntCounterVec};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    extractor::ExtractedDocument,
+    trafilatura::{TrafilaturaConfig, PYTHON_SCRIPT},
+};
+
+lazy_static! {
+    static ref TIMEOUTS_COUNTER: IntCounter = register_int_counter!(
+        "trafilatura_timeouts",
+        "Number of documents for which a trafilatura subprocess did not answer in time"
+    )
+    .unwrap();
+    static ref RESTARTS_COUNTER: IntCounterVec = register_int_counter_vec!(
+        "trafilatura_subprocess_restarts",
+        "Number of trafilatura subprocesses that were restarted, per reason",
+        &["reason"]
+    )
+    .unwrap();
+}
+
+/// Runs before the extraction script in every subprocess.
+/// trafilatura and its dependencies may print to stdout, which would corrupt the response frames.
+/// The frames are therefore written to a duplicate of the original stdout, and fd 1 is pointed at stderr.
+const PROTOCOL_SCRIPT: &str = r"
+import os
+import sys
+
+protocol_stdout = os.fdopen(os.dup(1), 'wb')
+os.dup2(2, 1)
+sys.stdout = sys.stderr
+";
+
+/// The request-response loop that runs in every subprocess, appended to [PYTHON_SCRIPT].
+const SERVER_SCRIPT: &str = r"
+import resource
+import struct
+
+def serve(memory_limit: int):
+    if memory_limit > 0:
+        resource.setrlimit(resource.RLIMIT_AS, (memory_limit, memory_limit))
+    stdin, stdout = sys.stdin.buffer, protocol_stdout
+    while True:
+        header = stdin.read(4)
+        if len(header) < 4:
+            return
+        (length,) = struct.unpack('>I', header)
+        request = json.loads(stdin.read(length))
+        try:
+            response = {'document': extract_document_dict(request['html'], **request['options'])}
+        except MemoryError:
+            response = {'error': 'out of memory', 'exit': True}
+        except Exception as e:
+            response = {'error': repr(e)}
+        payload = json.dumps(response).encode('utf-8')
+        stdout.write(struct.pack('>I', len(payload)))
+        stdout.write(payload)
+        stdout.flush()
+        if response.get('exit'):
+            sys.exit(1)
+
+serve(int(sys.argv[1]))
+";
+
+/// Configuration of the subprocess pool.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrafilaturaPoolConfig {
+    /// Number of Python subprocesses. Defaults to the number of available CPU cores.
+    pub size: usize,
+    /// Time after which a subprocess that has not answered is killed and the document is given up.
+    pub timeout_secs: u64,
+    /// Limit of the address space of every subprocess in megabytes. 0 disables the limit.
+    pub memory_limit_mb: u64,
+    /// The Python executable. trafilatura must be importable by it.
+    pub python: String,
+}
+
+impl Default for TrafilaturaPoolConfig {
+    fn default() -> Self {
+        Self {
+            size: std::thread::available_parallelism()
+                .map(Into::into)
+                .unwrap_or(1),
+            timeout_secs: 30,
+            memory_limit_mb: 2048,
+            python: "python3".to_string(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Request<'a> {
+    html: &'a str,
+    options: &'a TrafilaturaConfig,
+}
+
+#[derive(Deserialize)]
+struct Response {
+    document: Option<ExtractedDocument>,
+    error: Option<String>,
+    /// Whether the subprocess exits after this response, e.g. because it ran out of memory.
+    #[serde(default)]
+    exit: bool,
+}
+
+/// A running Python subprocess. A background thread reads response frames from its stdout.
+struct Subprocess {
+    child: Child,
+    stdin: ChildStdin,
+    responses: Receiver<std::io::Result<Vec<u8>>>,
+}
+
+impl Subprocess {
+    fn spawn(
+        config: &TrafilaturaPoolConfig,
+        script: &str,
+        stderr: Stdio,
+    ) -> Result<Self, anyhow::Error> {
+        let mut child = Command::new(&config.python)
+            .arg("-c")
+            .arg(script)
+            .arg((config.memory_limit_mb * 1024 * 1024).to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(stderr)
+            .spawn()
+            .with_context(|| format!("Failed to start {}", config.python))?;
+        let stdin = child.stdin.take().context("Subprocess has no stdin")?;
+        let stdout = child.stdout.take().context("Subprocess has no stdout")?;
+        let (sender, responses) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut stdout = BufReader::new(stdout);
+            loop {
+                let frame = read_frame(&mut stdout);
+                let failed = frame.is_err();
+                if sender.send(frame).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Ok(Self {
+            child,
+            stdin,
+            responses,
+        })
+    }
+
+    fn kill(mut self) {
+        if let Err(e) = self.child.kill() {
+            tracing::warn!("Failed to kill trafilatura subprocess: {}", e);
+        }
+        let _ = self.child.wait();
+    }
+}
+
+fn read_frame(reader: &mut impl Read) -> std::io::Result<Vec<u8>> {
+    let mut header = [0u8; 4];
+    reader.read_exact(&mut header)?;
+    let mut payload = vec![0u8; u32::from_be_bytes(header) as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+fn write_frame(writer: &mut impl Write, payload: &[u8]) -> std::io::Result<()> {
+    let length = u32::try_from(payload.len())
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Frame too large"))?;
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+/// A pool of Python subprocesses that run trafilatura.
+/// Slots that contain `None` have lost their subprocess and are restarted on their next use.
+pub struct TrafilaturaPool {
+    config: TrafilaturaPoolConfig,
+    /// The Python script that every subprocess runs.
+    script: String,
+    /// Where the subprocesses write their stderr, which is the worker's stderr except in tests.
+    stderr: fn() -> Stdio,
+    idle: Mutex<Vec<Option<Subprocess>>>,
+    available: Condvar,
+}
+
+impl TrafilaturaPool {
+    /// Starts all subprocesses of the pool.
+    pub fn new(config: TrafilaturaPoolConfig) -> Result<Self, anyhow::Error> {
+        Self::with_extraction_script(config, PYTHON_SCRIPT, Stdio::inherit)
+    }
+
+    /// Starts the subprocesses with a script that defines `extract_document_dict` instead of [PYTHON_SCRIPT].
+    fn with_extraction_script(
+        config: TrafilaturaPoolConfig,
+        extraction_script: &str,
+        stderr: fn() -> Stdio,
+    ) -> Result<Self, anyhow::Error> {
+        let script = format!(
+            "{}\n{}\n{}",
+            PROTOCOL_SCRIPT, extraction_script, SERVER_SCRIPT
+        );
+        let idle = (0..config.size.max(1))
+            .map(|_| Subprocess::spawn(&config, &script, stderr()).map(Some))
+            .collect::<Result<Vec<_>, _>>()?;
+        tracing::info!("Started {} trafilatura subprocesses", idle.len());
+        Ok(Self {
+            config,
+            script,
+            stderr,
+            idle: Mutex::new(idle),
+            available: Condvar::new(),
+        })
+    }
+
+    /// Extract text and metadata from `html` in one of the subprocesses.
+    /// Blocks until a subprocess is available and returns an error if it does not answer in time or crashes.
+    /// Might return `Ok(None)` if text extraction was not successful.
+    pub fn extract_with_metadata(
+        &self,
+        html: &str,
+        options: &TrafilaturaConfig,
+    ) -> Result<Option<ExtractedDocument>, anyhow::Error> {
+        let mut subprocess = match self.acquire() {
+            Some(subprocess) => subprocess,
+            None => match Subprocess::spawn(&self.config, &self.script, (self.stderr)()) {
+                Ok(subprocess) => subprocess,
+                Err(e) => {
+                    self.release(None);
+                    return Err(e);
+                }
+            },
+        };
+        let request = serde_json::to_vec(&Request { html, options })?;
+        if let Err(e) = write_frame(&mut subprocess.stdin, &request) {
+            RESTARTS_COUNTER.with_label_values(&["crashed"]).inc();
+            subprocess.kill();
+            self.release(None);
+            return Err(anyhow::anyhow!(
+                "Failed to send document to trafilatura: {}",
+                e
+            ));
+        }
+        let timeout = Duration::from_secs(self.config.timeout_secs);
+        let frame = match subprocess.responses.recv_timeout(timeout) {
+            Ok(Ok(frame)) => frame,
+            Ok(Err(_)) | Err(RecvTimeoutError::Disconnected) => {
+                RESTARTS_COUNTER.with_label_values(&["crashed"]).inc();
+                subprocess.kill();
+                self.release(None);
+                return Err(anyhow::anyhow!("trafilatura subprocess crashed"));
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                TIMEOUTS_COUNTER.inc();
+                RESTARTS_COUNTER.with_label_values(&["timeout"]).inc();
+                subprocess.kill();
+                self.release(None);
+                return Err(anyhow::anyhow!(
+                    "trafilatura did not answer within {:?}",
+                    timeout
+                ));
+            }
+        };
+        let response: Response = match serde_json::from_slice(&frame) {
+            Ok(response) => response,
+            Err(e) => {
+                self.release(Some(subprocess));
+                return Err(e.into());
+            }
+        };
+        if response.exit {
+            RESTARTS_COUNTER.with_label_values(&["exited"]).inc();
+            subprocess.kill();
+            self.release(None);
+        } else {
+            self.release(Some(subprocess));
+        }
+        match response.error {
+            Some(error) => Err(anyhow::anyhow!("trafilatura failed: {}", error)),
+            None => Ok(response.document),
+        }
+    }
+
+    /// Takes a slot from the pool and waits if all slots are in use.
+    fn acquire(&self) -> Option<Subprocess> {
+        let mut idle = self.idle.lock().unwrap();
+        loop {
+            if let Some(slot) = idle.pop() {
+                return slot;
+            }
+            idle = self.available.wait(idle).unwrap();
+        }
+    }
+
+    fn release(&self, slot: Option<Subprocess>) {
+        self.idle.lock().unwrap().push(slot);
+        self.available.notify_one();
+    }
+}
+
+impl Drop for TrafilaturaPool {
+    fn drop(&mut self) {
+        for subprocess in self.idle.get_mut().unwrap().drain(..).flatten() {
+            subprocess.kill();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process::Stdio;
+
+    use super::{
+        read_frame, write_frame, TrafilaturaPool, TrafilaturaPoolConfig, RESTARTS_COUNTER,
+    };
+    use crate::trafilatura::{OutputFormat, TrafilaturaConfig, PYTHON_SCRIPT};
+
+    /// Prints to stdout like some libraries do, which ends up on the discarded stderr, sleeps on `sleep`, crashes on `exit` and runs out of memory on `oom`.
+    const TEST_SCRIPT: &str = r"
+import json
+import time
+
+def extract_document_dict(content, **options):
+    print('Extracting', content)
+    if content == 'sleep':
+        time.sleep(60)
+    if content == 'exit':
+        os._exit(1)
+    if content == 'oom':
+        raise MemoryError()
+    return {'text': content}
+";
+
+    /// A fake trafilatura package whose `bare_extraction` returns the options that it was called with as text.
+    const FAKE_TRAFILATURA: &str = r"
+import json
+
+class Document:
+    def __init__(self, **fields):
+        self.fields = fields
+
+    def as_dict(self):
+        return self.fields
+
+def bare_extraction(content, **options):
+    return Document(
+        text=json.dumps(options, sort_keys=True),
+        title='Example',
+        author=None,
+        date='2024-07-22',
+        sitename='example.org',
+        description='An example page',
+        categories=('news', 'sports'),
+        tags=None,
+        url='https://example.org/canonical',
+        body=None,
+    )
+";
+
+    #[test]
+    fn can_round_trip_frames() {
+        let mut buffer = Vec::new();
+        write_frame(&mut buffer, b"{\"html\": \"<p>\"}").unwrap();
+        write_frame(&mut buffer, b"").unwrap();
+        assert_eq!(&buffer[..4], &[0, 0, 0, 15]);
+        let mut reader = buffer.as_slice();
+        assert_eq!(read_frame(&mut reader).unwrap(), b"{\"html\": \"<p>\"}");
+        assert_eq!(read_frame(&mut reader).unwrap(), b"");
+        assert!(read_frame(&mut reader).is_err());
+    }
+
+    #[test]
+    fn restarts_subprocesses_after_timeouts_and_crashes() {
+        let config = TrafilaturaPoolConfig {
+            size: 1,
+            timeout_secs: 1,
+            ..Default::default()
+        };
+        let pool =
+            TrafilaturaPool::with_extraction_script(config, TEST_SCRIPT, Stdio::null).unwrap();
+        let options = TrafilaturaConfig::default();
+        let extract = |html| pool.extract_with_metadata(html, &options);
+
+        assert_eq!(extract("first").unwrap().unwrap().text, "first");
+        let error = extract("sleep").unwrap_err().to_string();
+        assert!(error.contains("did not answer"), "{}", error);
+        assert_eq!(extract("second").unwrap().unwrap().text, "second");
+        let error = extract("exit").unwrap_err().to_string();
+        assert!(error.contains("crashed"), "{}", error);
+        assert_eq!(extract("third").unwrap().unwrap().text, "third");
+
+        // The subprocess exits after answering, so it is replaced instead of being handed out again.
+        let crashes = RESTARTS_COUNTER.with_label_values(&["crashed"]).get();
+        let error = extract("oom").unwrap_err().to_string();
+        assert!(error.contains("out of memory"), "{}", error);
+        assert_eq!(extract("fourth").unwrap().unwrap().text, "fourth");
+        assert_eq!(
+            RESTARTS_COUNTER.with_label_values(&["crashed"]).get(),
+            crashes
+        );
+    }
+
+    #[test]
+    fn passes_options_and_maps_metadata() {
+        let dir = std::env::temp_dir().join(format!("trafilatura-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("trafilatura")).unwrap();
+        std::fs::write(dir.join("trafilatura/__init__.py"), FAKE_TRAFILATURA).unwrap();
+        let script = format!(
+            "sys.path.insert(0, {:?})\n{}",
+            dir.to_string_lossy(),
+            PYTHON_SCRIPT
+        );
+        let config = TrafilaturaPoolConfig {
+            size: 1,
+            ..Default::default()
+        };
+        let pool = TrafilaturaPool::with_extraction_script(config, &script, Stdio::null).unwrap();
+        let options = TrafilaturaConfig {
+            favor_precision: true,
+            target_language: Some("de".to_string()),
+            output_format: OutputFormat::Markdown,
+            ..Default::default()
+        };
+        let document = pool
+            .extract_with_metadata(
+                "<html></html>",
+                &options.for_document(Some("https://example.org/")),
+            )
+            .unwrap()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let kwargs: serde_json::Value = serde_json::from_str(&document.text).unwrap();
+        assert_eq!(kwargs["favor_precision"], true);
+        assert_eq!(kwargs["include_tables"], false);
+        assert_eq!(kwargs["target_language"], "de");
+        assert_eq!(kwargs["url"], "https://example.org/");
+        assert_eq!(kwargs["with_metadata"], true);
+        assert_eq!(kwargs["include_comments"], false);
+        // Markdown is trafilatura's text output with formatting.
+        assert_eq!(kwargs["include_formatting"], true);
+        assert!(kwargs.get("output_format").is_none());
+        assert_eq!(document.title.as_deref(), Some("Example"));
+        assert_eq!(document.author, None);
+        assert_eq!(document.date.as_deref(), Some("2024-07-22"));
+        assert_eq!(document.sitename.as_deref(), Some("example.org"));
+        assert_eq!(document.description.as_deref(), Some("An example page"));
+        assert_eq!(document.categories, vec!["news", "sports"]);
+        assert!(document.tags.is_empty());
+        assert_eq!(
+            document.canonical_url.as_deref(),
+            Some("https://example.org/canonical")
+        );
+    }
+}